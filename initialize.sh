@@ -20,6 +20,7 @@ record {
     get_logs_address = opt vec { "${ENDPOINT_31337}" };
     last_scraped_block_number = opt 0: opt nat;
     block_tag = opt variant { Latest = null };
+    confirmations = opt 1 : opt nat64;
 }
 EOM
 
@@ -42,6 +43,7 @@ record {
     get_logs_address = opt vec { "${ENDPOINT_31338}" };
     last_scraped_block_number = opt 0: opt nat;
     block_tag = opt variant { Latest = null };
+    confirmations = opt 1 : opt nat64;
 }
 EOM
 
@@ -1,8 +1,12 @@
-use candid::CandidType;
+use std::collections::{BTreeMap, BTreeSet};
+use candid::{CandidType, Deserialize, Principal};
 use ethers_core::types::H160;
 use thiserror::Error;
 use serde_bytes::ByteBuf;
 use crate::declarations::ic_siwe_provider::{ic_siwe_provider, GetAddressResponse};
+use crate::audit;
+use crate::state::{mutate_state, read_state};
+use crate::HarmonizeError;
 
 #[derive(Debug, Error, CandidType)]
 pub enum SignInError {
@@ -16,6 +20,93 @@ pub enum SignInError {
     InvalidAddress,
 }
 
+#[derive(Debug, Error, CandidType)]
+pub enum RoleError {
+    #[error("The last admin cannot give up the role")]
+    LastAdmin,
+}
+
+/// What a principal is allowed to manage. A principal can hold several roles.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Grants and revokes roles, and sets the fees and the treasury.
+    Admin,
+    /// Configures networks, tokens, canonical assets and withdrawal limits.
+    Operator,
+    /// Pauses and resumes operations.
+    Pauser,
+    /// Reads the administrative state.
+    Auditor,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Admin, Role::Operator, Role::Pauser, Role::Auditor];
+}
+
+#[derive(Default)]
+pub struct Roles {
+    pub grants: BTreeMap<Principal, BTreeSet<Role>>,
+}
+
+impl Roles {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn has(&self, principal: &Principal, role: Role) -> bool {
+        self.grants.get(principal).is_some_and(|roles| roles.contains(&role))
+    }
+
+    pub fn grant(&mut self, principal: Principal, role: Role) {
+        self.grants.entry(principal).or_default().insert(role);
+    }
+
+    /// Revokes `role` from `principal`, unless that would leave the canister without an admin.
+    pub fn revoke(&mut self, principal: Principal, role: Role) -> Result<(), RoleError> {
+        if role == Role::Admin && self.holders(Role::Admin).all(|admin| admin == principal) {
+            return Err(RoleError::LastAdmin);
+        }
+        if let Some(roles) = self.grants.get_mut(&principal) {
+            roles.remove(&role);
+            if roles.is_empty() {
+                self.grants.remove(&principal);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn holders(&self, role: Role) -> impl Iterator<Item = Principal> + '_ {
+        self.grants
+            .iter()
+            .filter(move |(_, roles)| roles.contains(&role))
+            .map(|(principal, _)| *principal)
+    }
+
+    pub fn of(&self, principal: &Principal) -> Vec<Role> {
+        self.grants.get(principal).map(|roles| roles.iter().copied().collect()).unwrap_or_default()
+    }
+}
+
+pub fn caller_has_role(role: Role) -> bool {
+    read_state(|s| s.roles.has(&ic_cdk::caller(), role))
+}
+
+/// Traps unless the caller holds `role`.
+pub fn require_role(role: Role) {
+    if !caller_has_role(role) {
+        ic_cdk::trap(&format!("The caller lacks the {:?} role.", role));
+    }
+}
+
+/// Traps unless the caller holds at least one of `roles`.
+pub fn require_any_role(roles: &[Role]) {
+    if !roles.iter().any(|role| caller_has_role(*role)) {
+        ic_cdk::trap(&format!("The caller lacks any of the {:?} roles.", roles));
+    }
+}
+
+// Public API
+
 pub async fn get_siwe_session_address() -> Result<H160, SignInError> {
     let response = ic_siwe_provider
         .get_address(ByteBuf::from(ic_cdk::caller().as_slice()))
@@ -28,4 +119,62 @@ pub async fn get_siwe_session_address() -> Result<H160, SignInError> {
     };
 
     address.parse().map_err(|_| SignInError::InvalidAddress)
-}
\ No newline at end of file
+}
+
+pub fn grant_role(principal: Principal, role: Role) {
+    require_role(Role::Admin);
+    let (old_roles, new_roles) = mutate_state(|s| {
+        let old_roles = s.roles.of(&principal);
+        s.roles.grant(principal, role);
+        (old_roles, s.roles.of(&principal))
+    });
+    audit::record_change("grant_role", (principal, role), old_roles, new_roles);
+}
+
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), HarmonizeError> {
+    require_role(Role::Admin);
+    let (old_roles, new_roles) = mutate_state(|s| -> Result<_, RoleError> {
+        let old_roles = s.roles.of(&principal);
+        s.roles.revoke(principal, role)?;
+        Ok((old_roles, s.roles.of(&principal)))
+    })?;
+    audit::record_change("revoke_role", (principal, role), old_roles, new_roles);
+    Ok(())
+}
+
+pub fn get_roles(principal: Principal) -> Vec<Role> {
+    read_state(|s| s.roles.of(&principal))
+}
+
+/// Lists every principal with its roles.
+pub fn get_role_holders() -> Vec<(Principal, Vec<Role>)> {
+    require_any_role(&[Role::Admin, Role::Auditor]);
+    read_state(|s| {
+        s.roles
+            .grants
+            .iter()
+            .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_the_last_admin_cannot_be_revoked() {
+        let mut roles = Roles::new();
+        let (alice, bob) = (Principal::anonymous(), Principal::management_canister());
+        roles.grant(alice, Role::Admin);
+        roles.grant(alice, Role::Pauser);
+        assert!(matches!(roles.revoke(alice, Role::Admin), Err(RoleError::LastAdmin)));
+
+        roles.grant(bob, Role::Admin);
+        roles.revoke(alice, Role::Admin).unwrap();
+        assert_eq!(roles.of(&alice), vec![Role::Pauser]);
+        assert!(!roles.has(&alice, Role::Admin));
+        roles.revoke(alice, Role::Pauser).unwrap();
+        assert!(roles.grants.get(&alice).is_none());
+    }
+}
@@ -0,0 +1,123 @@
+//! An append-only log of privileged calls.
+//!
+//! The log lives in its own stable memory rather than in the state snapshot,
+//! so entries are durable as soon as they are written and survive upgrades
+//! even if the snapshot cannot be restored.
+
+use std::{borrow::Cow, cell::RefCell, fmt::Debug};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{storable::Bound, StableLog, Storable};
+
+use crate::{
+    access_control::{require_any_role, Role},
+    storage::{get_memory, Memory, AUDIT_LOG_DATA, AUDIT_LOG_INDEX},
+};
+
+/// The maximum number of entries returned by a single page.
+pub const MAX_AUDIT_ENTRIES_PER_PAGE: u64 = 100;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// Nanoseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub caller: Principal,
+    /// The name of the endpoint that was called.
+    pub method: String,
+    /// The arguments of the call.
+    pub args: String,
+    /// The value replaced by the call, for calls that change a single setting.
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("BUG: failed to encode an audit entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("BUG: failed to decode an audit entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AuditPage {
+    /// The total number of entries in the log.
+    pub total: u64,
+    pub entries: Vec<AuditEntry>,
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(
+        StableLog::init(get_memory(AUDIT_LOG_INDEX), get_memory(AUDIT_LOG_DATA))
+            .expect("BUG: failed to initialize the audit log")
+    );
+}
+
+/// Appends an entry to the log and returns its id.
+fn append(caller: Principal, timestamp: u64, method: &str, args: String, old_value: Option<String>, new_value: Option<String>) -> u64 {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entry = AuditEntry { id: log.len(), timestamp, caller, method: method.to_string(), args, old_value, new_value };
+        log.append(&entry).expect("BUG: failed to append to the audit log")
+    })
+}
+
+/// Returns up to `limit` entries, oldest first, starting at id `start`.
+fn page(start: u64, limit: u64) -> AuditPage {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = start.saturating_add(limit.min(MAX_AUDIT_ENTRIES_PER_PAGE)).min(log.len());
+        AuditPage {
+            total: log.len(),
+            entries: (start..end).filter_map(|id| log.get(id)).collect(),
+        }
+    })
+}
+
+/// Records a privileged call of the caller with its arguments.
+pub fn record(method: &str, args: impl Debug) {
+    append(ic_cdk::caller(), ic_cdk::api::time(), method, format!("{:?}", args), None, None);
+}
+
+/// Records a privileged call of the caller that replaced `old_value` with `new_value`.
+pub fn record_change(method: &str, args: impl Debug, old_value: impl Debug, new_value: impl Debug) {
+    append(
+        ic_cdk::caller(),
+        ic_cdk::api::time(),
+        method,
+        format!("{:?}", args),
+        Some(format!("{:?}", old_value)),
+        Some(format!("{:?}", new_value)),
+    );
+}
+
+// Public API
+
+pub fn get_audit_log(start: u64, limit: u64) -> AuditPage {
+    require_any_role(&[Role::Admin, Role::Auditor]);
+    page(start, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_appended_in_order() {
+        let caller = Principal::anonymous();
+        for timestamp in 0..3 {
+            let id = append(caller, timestamp, "set_treasury", "()".to_string(), Some("a".to_string()), Some("b".to_string()));
+            assert_eq!(id, timestamp);
+        }
+
+        let entries = page(1, 10);
+        assert_eq!(entries.total, 3);
+        assert_eq!(entries.entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(entries.entries[0].timestamp, 1);
+        assert!(page(3, 10).entries.is_empty());
+    }
+}
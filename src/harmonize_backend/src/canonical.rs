@@ -0,0 +1,253 @@
+//! Canonical assets, which unify the same token deployed on several networks.
+//!
+//! Deposits of a mapped token are credited to the canonical asset, and the
+//! canonical balance can be withdrawn on any mapped network where the canister
+//! holds enough of the token. That inventory is tracked per network as the
+//! liquidity of the asset, which ERC-20 balances of a mapped token draw from
+//! as well.
+
+use std::collections::BTreeMap;
+use candid::{CandidType, Deserialize};
+use ethers_core::types::{H160, U256};
+use thiserror::Error;
+
+use crate::{
+    access_control::{require_role, Role},
+    audit,
+    chain_fusion::job::safe,
+    state::{mutate_state, read_state},
+    types::{H160t, U256t},
+    wallet::{self, Account, AssetId},
+    HarmonizeError,
+};
+
+#[derive(Error, Debug, CandidType)]
+pub enum CanonicalAssetError {
+    #[error("The canonical asset does not exist")]
+    NotFound,
+    #[error("The token is not listed")]
+    TokenNotListed,
+    #[error("The token is already mapped to a canonical asset")]
+    TokenAlreadyMapped,
+    #[error("The canonical asset already has a token on this network")]
+    ChainAlreadyMapped,
+    #[error("The token has {actual} decimals, the canonical asset has {expected}")]
+    DecimalsMismatch { expected: u8, actual: u8 },
+    #[error("The canonical asset has no token on this network")]
+    UnsupportedChain,
+    #[error("Insufficient liquidity on this network, {available} available")]
+    InsufficientLiquidity { available: U256t },
+    #[error("{held} of the token is held for pending withdrawals")]
+    TokenHeld { held: U256t },
+}
+
+/// The token of a canonical asset on one network.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CanonicalChain {
+    pub chain_id: u32,
+    pub token: H160t,
+    /// The amount of the token held by the canister on behalf of canonical and ERC-20 balances.
+    pub liquidity: U256t,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CanonicalAsset {
+    pub id: u32,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub chains: Vec<CanonicalChain>,
+}
+
+impl CanonicalAsset {
+    pub fn chain(&self, chain_id: u32) -> Option<&CanonicalChain> {
+        self.chains.iter().find(|chain| chain.chain_id == chain_id)
+    }
+
+    pub fn chain_mut(&mut self, chain_id: u32) -> Option<&mut CanonicalChain> {
+        self.chains.iter_mut().find(|chain| chain.chain_id == chain_id)
+    }
+}
+
+#[derive(Default)]
+pub struct CanonicalAssets {
+    pub assets: BTreeMap<u32, CanonicalAsset>,
+}
+
+impl CanonicalAssets {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&CanonicalAsset> {
+        self.assets.get(&id)
+    }
+
+    pub fn create(&mut self, name: String, symbol: String, decimals: u8) -> u32 {
+        let id = self.assets.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.assets.insert(id, CanonicalAsset { id, name, symbol, decimals, chains: vec![] });
+        id
+    }
+
+    /// Returns the canonical asset the token is mapped to, if any.
+    pub fn find_by_token(&self, chain_id: u32, token: H160) -> Option<&CanonicalAsset> {
+        self.assets
+            .values()
+            .find(|asset| asset.chain(chain_id).is_some_and(|chain| chain.token.0 == token))
+    }
+
+    /// Maps `token` to the canonical asset, starting with the `inventory` of the token
+    /// that the canister already holds as liquidity.
+    pub fn map_token(&mut self, id: u32, chain_id: u32, token: H160, decimals: u8, inventory: U256) -> Result<(), CanonicalAssetError> {
+        if self.find_by_token(chain_id, token).is_some() {
+            return Err(CanonicalAssetError::TokenAlreadyMapped);
+        }
+        let asset = self.assets.get_mut(&id).ok_or(CanonicalAssetError::NotFound)?;
+        if asset.decimals != decimals {
+            return Err(CanonicalAssetError::DecimalsMismatch { expected: asset.decimals, actual: decimals });
+        }
+        if asset.chain(chain_id).is_some() {
+            return Err(CanonicalAssetError::ChainAlreadyMapped);
+        }
+        asset.chains.push(CanonicalChain { chain_id, token: token.into(), liquidity: inventory.into() });
+        Ok(())
+    }
+
+    /// Adds a deposit of a mapped token to the liquidity of its canonical asset and
+    /// returns the asset to credit.
+    pub fn deposit(&mut self, chain_id: u32, token: H160, amount: U256) -> Option<AssetId> {
+        let asset = self
+            .assets
+            .values_mut()
+            .find(|asset| asset.chain(chain_id).is_some_and(|chain| chain.token.0 == token))?;
+        let chain = asset.chain_mut(chain_id).expect("BUG: the chain was just found");
+        chain.liquidity = chain.liquidity.0.saturating_add(amount).into();
+        Some(AssetId::Canonical { id: asset.id })
+    }
+
+    /// Takes `amount` out of the liquidity on `chain_id` and returns the token to send.
+    pub fn reserve_liquidity(&mut self, id: u32, chain_id: u32, amount: U256) -> Result<H160, CanonicalAssetError> {
+        let asset = self.assets.get_mut(&id).ok_or(CanonicalAssetError::NotFound)?;
+        let chain = asset.chain_mut(chain_id).ok_or(CanonicalAssetError::UnsupportedChain)?;
+        if chain.liquidity.0 < amount {
+            return Err(CanonicalAssetError::InsufficientLiquidity { available: chain.liquidity });
+        }
+        chain.liquidity = (chain.liquidity.0 - amount).into();
+        Ok(chain.token.0)
+    }
+
+    /// Takes `amount` out of the liquidity of the canonical asset `token` is mapped to,
+    /// if any, and returns the id of that asset.
+    pub fn reserve_token_liquidity(&mut self, chain_id: u32, token: H160, amount: U256) -> Result<Option<u32>, CanonicalAssetError> {
+        let Some(id) = self.find_by_token(chain_id, token).map(|asset| asset.id) else {
+            return Ok(None);
+        };
+        self.reserve_liquidity(id, chain_id, amount)?;
+        Ok(Some(id))
+    }
+
+    /// Gives back liquidity taken by [`CanonicalAssets::reserve_token_liquidity`], if `token`
+    /// is mapped to a canonical asset.
+    pub fn restore_token_liquidity(&mut self, chain_id: u32, token: H160, amount: U256) {
+        if let Some(id) = self.find_by_token(chain_id, token).map(|asset| asset.id) {
+            self.restore_liquidity(id, chain_id, amount);
+        }
+    }
+
+    /// Gives back liquidity taken by [`CanonicalAssets::reserve_liquidity`].
+    pub fn restore_liquidity(&mut self, id: u32, chain_id: u32, amount: U256) {
+        if let Some(chain) = self.assets.get_mut(&id).and_then(|asset| asset.chain_mut(chain_id)) {
+            chain.liquidity = chain.liquidity.0.saturating_add(amount).into();
+        }
+    }
+}
+
+// Public API
+
+pub fn create_canonical_asset(name: String, symbol: String, decimals: u8) -> u32 {
+    require_role(Role::Operator);
+    let id = mutate_state(|s| s.canonical_assets.create(name.clone(), symbol.clone(), decimals));
+    audit::record("create_canonical_asset", (name, symbol, decimals, id));
+    id
+}
+
+/// Maps a listed token to a canonical asset. Later deposits of the token are
+/// credited to the canonical asset, and the ERC-20 balances already deposited
+/// become part of its liquidity. Tokens with pending withdrawals cannot be mapped.
+pub fn map_canonical_token(id: u32, chain_id: u32, token: H160) -> Result<(), HarmonizeError> {
+    require_role(Role::Operator);
+    mutate_state(|s| -> Result<(), HarmonizeError> {
+        let metadata = s.tokens.get(chain_id, token).ok_or(CanonicalAssetError::TokenNotListed)?;
+        // Settling a pending withdrawal restores the liquidity of the mapping it started under
+        let asset = AssetId::Erc20 { chain_id, token: token.into() };
+        let held = s.wallets.total_held(&asset);
+        if !held.is_zero() {
+            return Err(CanonicalAssetError::TokenHeld { held: held.into() }.into());
+        }
+        let inventory = s.wallets.total_available(&asset);
+        s.canonical_assets.map_token(id, chain_id, token, metadata.decimals, inventory)?;
+        Ok(())
+    })?;
+    audit::record("map_canonical_token", (id, chain_id, token));
+    Ok(())
+}
+
+pub fn get_canonical_assets() -> Vec<CanonicalAsset> {
+    read_state(|s| s.canonical_assets.assets.values().cloned().collect())
+}
+
+/// Withdraws a canonical balance as the mapped token on `chain_id` and returns the hash of the transaction.
+pub async fn withdraw_canonical(from: Account, to: H160, id: u32, chain_id: u32, amount: U256) -> Result<String, HarmonizeError> {
+    let token = mutate_state(|s| s.canonical_assets.reserve_liquidity(id, chain_id, amount))?;
+    let asset = AssetId::Canonical { id };
+    let outflow = AssetId::Erc20 { chain_id, token: token.into() };
+    let result = wallet::withdraw_with(
+        from,
+        to,
+        asset,
+        outflow,
+        amount,
+        safe::transfer_erc20(chain_id, token, from, to, amount, None, None),
+    )
+    .await;
+    if result.is_err() {
+        mutate_state(|s| s.canonical_assets.restore_liquidity(id, chain_id, amount));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_withdrawals_are_bounded_by_liquidity() {
+        let mut assets = CanonicalAssets::new();
+        let id = assets.create("USD Coin".to_string(), "USDC".to_string(), 6);
+        let (token_a, token_b) = (H160::repeat_byte(1), H160::repeat_byte(2));
+        assets.map_token(id, 1, token_a, 6, U256::zero()).unwrap();
+        assets.map_token(id, 2, token_b, 6, U256::from(5)).unwrap();
+        assert!(matches!(assets.map_token(id, 3, token_a, 6, U256::zero()), Err(CanonicalAssetError::TokenAlreadyMapped)));
+        assert!(matches!(
+            assets.map_token(id, 3, H160::repeat_byte(3), 18, U256::zero()),
+            Err(CanonicalAssetError::DecimalsMismatch { expected: 6, actual: 18 })
+        ));
+
+        assert_eq!(assets.deposit(1, token_a, U256::from(100)), Some(AssetId::Canonical { id }));
+        assert_eq!(assets.deposit(1, H160::repeat_byte(9), U256::from(100)), None);
+
+        // Funds deposited on one network can leave through another only if it holds enough
+        assert!(matches!(
+            assets.reserve_liquidity(id, 2, U256::from(6)),
+            Err(CanonicalAssetError::InsufficientLiquidity { .. })
+        ));
+        assert_eq!(assets.reserve_liquidity(id, 1, U256::from(60)).unwrap(), token_a);
+        assets.restore_liquidity(id, 1, U256::from(10));
+        assert_eq!(assets.get(id).unwrap().chain(1).unwrap().liquidity.0, U256::from(50));
+
+        // ERC-20 withdrawals of a mapped token draw from the same liquidity
+        assert_eq!(assets.reserve_token_liquidity(2, token_b, U256::from(5)).unwrap(), Some(id));
+        assert_eq!(assets.reserve_token_liquidity(2, H160::repeat_byte(9), U256::from(5)).unwrap(), None);
+        assert!(assets.reserve_token_liquidity(2, token_b, U256::from(1)).is_err());
+    }
+}
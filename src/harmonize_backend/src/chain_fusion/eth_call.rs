@@ -1,149 +1,99 @@
-// use ethers_core::abi::{Contract, FunctionExt, Token};
-// use ethers_core::types::U256;
-// use hex::FromHexError;
-// use serde::{Deserialize, Serialize};
+use candid::CandidType;
+use ethers_core::abi::{Function, Token};
+use ethers_core::types::H160;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-// use crate::chain_fusion::evm_rpc::{RequestResult, EVM_RPC};
-// use crate::state::read_network_state;
+use crate::chain_fusion::evm_rpc::{RequestResult, EVM_RPC};
+use crate::state::read_network_state;
+use crate::types::H160Ext;
 
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// pub struct EthCallParams {
-//     pub to: String,
-//     pub data: String,
-// }
+#[derive(Error, Debug, CandidType)]
+pub enum EthCallError {
+    #[error("Failed to encode the call: {0}")]
+    EncodingFailed(String),
+    #[error("The RPC call failed: {0}")]
+    RpcCallFailed(String),
+    #[error("The RPC call returned an error: {0}")]
+    ExecutionFailed(String),
+    #[error("Failed to decode the result: {0}")]
+    DecodingFailed(String),
+}
 
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// pub struct JsonRpcRequest {
-//     pub id: u64,
-//     pub jsonrpc: String,
-//     pub method: String,
-//     pub params: (EthCallParams, String),
-// }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EthCallParams {
+    to: String,
+    data: String,
+}
 
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// pub struct JsonRpcResult {
-//     result: Option<String>,
-//     error: Option<JsonRpcError>,
-// }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct JsonRpcRequest {
+    id: u64,
+    jsonrpc: String,
+    method: String,
+    params: (EthCallParams, String),
+}
 
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// pub struct JsonRpcError {
-//     code: isize,
-//     message: String,
-// }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct JsonRpcResult {
+    result: Option<String>,
+    error: Option<JsonRpcError>,
+}
 
-// async fn eth_call(
-//     network_id: u32,
-//     contract_address: String,
-//     abi: &Contract,
-//     function_name: &str,
-//     args: &[Token],
-//     block_number: &str,
-// ) -> Vec<Token> {
-//     let f = match abi.functions_by_name(function_name).map(|v| &v[..]) {
-//         Ok([f]) => f,
-//         Ok(fs) => panic!(
-//             "Found {} function overloads. Please pass one of the following: {}",
-//             fs.len(),
-//             fs.iter()
-//                 .map(|f| format!("{:?}", f.abi_signature()))
-//                 .collect::<Vec<_>>()
-//                 .join(", ")
-//         ),
-//         Err(_) => abi
-//             .functions()
-//             .find(|f| function_name == f.abi_signature())
-//             .expect("Function not found"),
-//     };
-//     let data = f
-//         .encode_input(args)
-//         .expect("Error while encoding input args");
-//     let json_rpc_payload = serde_json::to_string(&JsonRpcRequest {
-//         id: 1,
-//         jsonrpc: "2.0".to_string(),
-//         method: "eth_call".to_string(),
-//         params: (
-//             EthCallParams {
-//                 to: contract_address,
-//                 data: to_hex(&data),
-//             },
-//             block_number.to_string(),
-//         ),
-//     })
-//     .expect("Error while encoding JSON-RPC request");
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct JsonRpcError {
+    code: isize,
+    message: String,
+}
 
-//     let rpc_provider = read_network_state(network_id, |s| s.rpc_service.clone());
-//     let max_response_bytes = 2048;
-//     let cycles = 10_000_000_000;
+/// Calls a view function of a contract at the latest block and decodes its output.
+pub async fn eth_call(
+    network_id: u32,
+    contract_address: H160,
+    function: &Function,
+    args: &[Token],
+) -> Result<Vec<Token>, EthCallError> {
+    let data = function
+        .encode_input(args)
+        .map_err(|e| EthCallError::EncodingFailed(e.to_string()))?;
+    let json_rpc_payload = serde_json::to_string(&JsonRpcRequest {
+        id: 1,
+        jsonrpc: "2.0".to_string(),
+        method: "eth_call".to_string(),
+        params: (
+            EthCallParams {
+                to: contract_address.to_repr(),
+                data: format!("0x{}", hex::encode(data)),
+            },
+            "latest".to_string(),
+        ),
+    })
+    .map_err(|e| EthCallError::EncodingFailed(e.to_string()))?;
 
-//     let res = match EVM_RPC
-//         .request(rpc_provider, json_rpc_payload, max_response_bytes, cycles)
-//         .await
-//     {
-//         Ok((res,)) => res,
-//         Err(e) => ic_cdk::trap(format!("Error: {:?}", e).as_str()),
-//     };
+    let rpc_service = read_network_state(network_id, |s| s.rpc_service.clone());
+    let max_response_bytes = 2048;
+    let cycles = 10_000_000_000;
 
-//     match res {
-//         RequestResult::Ok(ok) => {
-//             let json: JsonRpcResult =
-//                 serde_json::from_str(&ok).expect("JSON was not well-formatted");
-//             let result = from_hex(&json.result.expect("Unexpected JSON response")).unwrap();
-//             f.decode_output(&result).expect("Error decoding output")
-//         }
-//         RequestResult::Err(err) => panic!("Response error: {err:?}"),
-//     }
-// }
+    let (result,) = EVM_RPC
+        .request(rpc_service, json_rpc_payload, max_response_bytes, cycles)
+        .await
+        .map_err(|(code, message)| EthCallError::RpcCallFailed(format!("{:?} {}", code, message)))?;
 
-// fn to_hex(data: &[u8]) -> String {
-//     format!("0x{}", hex::encode(data))
-// }
-
-// fn from_hex(data: &str) -> Result<Vec<u8>, FromHexError> {
-//     hex::decode(&data[2..])
-// }
-
-// pub async fn erc20_balance_of(network_id: u32, contract_address: String, account: String) -> U256 {
-//     // Define the ABI JSON as a string literal
-//     let abi_json = r#"
-//    [
-//        {
-//            "constant": true,
-//            "inputs": [
-//                {
-//                    "name": "_owner",
-//                    "type": "address"
-//                }
-//            ],
-//            "name": "balanceOf",
-//            "outputs": [
-//                {
-//                    "name": "balance",
-//                    "type": "uint256"
-//                }
-//            ],
-//            "type": "function"
-//        }
-//    ]
-//    "#;
-//     let abi =
-//         serde_json::from_str::<ethers_core::abi::Contract>(abi_json).expect("should serialise");
-
-//     let Token::Uint(balance) = eth_call(
-//         network_id,
-//         contract_address.to_string(),
-//         &abi,
-//         "balanceOf",
-//         &[Token::Address(
-//             account.parse().expect("address should be valid"),
-//         )],
-//         "latest",
-//     )
-//     .await
-//     .first()
-//     .unwrap()
-//     .clone() else {
-//         panic!("oops")
-//     };
-//     balance
-// }
+    let response = match result {
+        RequestResult::Ok(response) => response,
+        RequestResult::Err(e) => return Err(EthCallError::RpcCallFailed(format!("{:?}", e))),
+    };
+    let json: JsonRpcResult = serde_json::from_str(&response)
+        .map_err(|e| EthCallError::DecodingFailed(e.to_string()))?;
+    if let Some(error) = json.error {
+        return Err(EthCallError::ExecutionFailed(format!("{} ({})", error.message, error.code)));
+    }
+    let result = json
+        .result
+        .ok_or_else(|| EthCallError::DecodingFailed("missing result".to_string()))?;
+    let bytes = hex::decode(result.trim_start_matches("0x"))
+        .map_err(|e| EthCallError::DecodingFailed(e.to_string()))?;
+    function
+        .decode_output(&bytes)
+        .map_err(|e| EthCallError::DecodingFailed(e.to_string()))
+}
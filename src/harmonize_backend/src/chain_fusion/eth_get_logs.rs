@@ -1,6 +1,6 @@
 use std::{
-    cmp::{min, Ordering},
-    ops::{Add, Div, Sub},
+    cmp::{max, min, Ordering},
+    ops::{Add, Sub},
     time::Duration,
 };
 
@@ -9,23 +9,28 @@ use ic_cdk::println;
 
 use crate::{chain_fusion::{
     evm_rpc::{
-        BlockTag, GetBlockByNumberResult, GetLogsArgs, GetLogsResult, HttpOutcallError,
+        Block, BlockTag, GetBlockByNumberResult, GetLogsArgs, GetLogsResult, HttpOutcallError, JsonRpcError,
         MultiGetBlockByNumberResult, MultiGetLogsResult, RejectionCode, RpcError, EVM_RPC,
-    }, guard::TimerGuard, job::handle_event, TaskType
-}, state::Network, types::H160Ext};
-use crate::state::{read_state, read_network_state, mutate_network_state};
+    }, guard::{TimerGuard, TimerGuardError}, job::handle_event, TaskType
+}, pause::{self, PausableOperation}, state::Network, types::H160Ext};
+use crate::state::{read_network_state, mutate_network_state};
 
 use super::evm_rpc::LogEntry;
 
 async fn process_logs(network_id: u32) {
     // TODO: Move guard up one level
-    let _guard = match TimerGuard::new(TaskType::ProcessLogs) {
+    let _guard = match TimerGuard::new(TaskType::ProcessLogs, network_id) {
         Ok(guard) => guard,
         Err(_) => return,
     };
 
     let logs_to_process = read_network_state(network_id, |s| (s.logs_to_process.clone()));
     for (event_source, event) in logs_to_process {
+        // Unprocessed logs are kept until deposits are resumed
+        if let Err(e) = pause::check(PausableOperation::DepositCrediting, Some(network_id)) {
+            println!("[process_logs]: {e}");
+            return;
+        }
         handle_event(network_id, event_source, event).await
     }
 }
@@ -46,7 +51,7 @@ pub enum GetLogsError {
 
 pub async fn get_logs(network_id: u32, from: &Nat, to: &Nat) -> Result<Vec<LogEntry>, GetLogsError> {
     let get_logs_address = read_network_state(network_id, |s| s.get_logs_address.clone());
-    // let get_logs_topics = read_state(|s| s.get_logs_topics.clone());
+    let get_logs_topics = read_network_state(network_id, |s| s.get_logs_topics.clone());
     let rpc_services = read_network_state(network_id, |s| s.rpc_services.clone());
 
     if get_logs_address.is_empty() {
@@ -54,18 +59,12 @@ pub async fn get_logs(network_id: u32, from: &Nat, to: &Nat) -> Result<Vec<LogEn
         return Ok(vec![]);
     }
 
-    // if get_logs_topics.is_none() {
-    //     println!("No topics to query logs for");
-    //     return Ok(vec![]);
-    // }
-
-    let get_logs_topics = None;
-
     let get_logs_args: GetLogsArgs = GetLogsArgs {
         fromBlock: Some(BlockTag::Number(from.clone())),
         toBlock: Some(BlockTag::Number(to.clone())),
         addresses: get_logs_address.into_iter().map(|a| a.to_repr()).collect(),
-        topics: get_logs_topics,
+        // Without filters, every log of the endpoint is fetched
+        topics: (!get_logs_topics.is_empty()).then_some(get_logs_topics),
     };
 
     let cycles = 10_000_000_000;
@@ -86,18 +85,21 @@ pub async fn get_logs(network_id: u32, from: &Nat, to: &Nat) -> Result<Vec<LogEn
     }
 }
 
-/// Scraps Ethereum logs between `from` and `min(from + MAX_BLOCK_SPREAD, to)` since certain RPC providers
-/// require that the number of blocks queried is no greater than MAX_BLOCK_SPREAD.
-/// Returns the last block number that was scraped (which is `min(from + MAX_BLOCK_SPREAD, to)`) if there
-/// was no error when querying the providers, otherwise returns `None`.
+/// The default maximum block spread, introduced by Alchemy limits.
+pub const DEFAULT_MAX_BLOCK_SPREAD: u64 = 500;
+
+/// Scraps Ethereum logs between `from` and `min(from + block_spread, to)` since certain RPC providers
+/// limit the number of blocks or logs queried at once. The spread of the network shrinks when a
+/// provider rejects the range, and grows back towards its maximum after successful calls.
+/// Returns the last block number that was scraped if there was no error when querying the providers,
+/// otherwise returns `None`. A single block that is still rejected is retried on the next scrape,
+/// since skipping it would lose its deposits.
 async fn scrape_eth_logs_range_inclusive(network_id: u32, from: &Nat, to: &Nat) -> Option<Nat> {
-    /// The maximum block spread is introduced by Alchemy limits.
-    /// TODO: Make this configurable.
-    const MAX_BLOCK_SPREAD: u16 = 500;
     match from.cmp(to) {
         Ordering::Less | Ordering::Equal => {
-            let max_to = from.clone().add(Nat::from(MAX_BLOCK_SPREAD));
-            let mut last_block_number = min(max_to, to.clone());
+            let remaining: u64 = to.clone().sub(from.clone()).0.try_into().unwrap_or(u64::MAX);
+            let mut spread = min(read_network_state(network_id, |s| s.block_spread), remaining);
+            let mut last_block_number = from.clone().add(Nat::from(spread));
             println!(
                 "Scraping ETH logs from block {:?} to block {:?}...",
                 from, last_block_number
@@ -110,55 +112,26 @@ async fn scrape_eth_logs_range_inclusive(network_id: u32, from: &Nat, to: &Nat)
                         println!(
                           "Failed to get ETH logs from block {from} to block {last_block_number}: {e:?}",
                         );
-                        match e {
-                            GetLogsError::RpcError(RpcError::HttpOutcallError(e)) => {
-                                if e.is_response_too_large() {
-                                    if *from == last_block_number {
-                                        mutate_network_state(network_id, |s| {
-                                            s.record_skipped_block(last_block_number.clone());
-                                            s.last_scraped_block_number = last_block_number.clone();
-                                        });
-                                        return Some(last_block_number);
-                                    } else {
-                                        let new_last_block_number = from.clone().add(
-                                            last_block_number
-                                                .clone()
-                                                .sub(from.clone())
-                                                .div(Nat::from(2u32)),
-                                        );
-                                        println!( "Too many logs received in range [{from}, {last_block_number}]. Will retry with range [{from}, {new_last_block_number}]");
-                                        last_block_number = new_last_block_number;
-                                        continue;
-                                    }
-                                }
-                            }
-                            _ => return None,
+                        if !e.is_range_too_large() {
+                            return None;
+                        }
+                        if spread == 0 {
+                            println!("Block {from} is rejected on its own, will retry it on the next scrape");
+                            return None;
                         }
+                        spread /= 2;
+                        mutate_network_state(network_id, |s| s.block_spread = spread);
+                        let new_last_block_number = from.clone().add(Nat::from(spread));
+                        println!( "Range [{from}, {last_block_number}] is too large. Will retry with range [{from}, {new_last_block_number}]");
+                        last_block_number = new_last_block_number;
                     }
                 };
             };
+            mutate_network_state(network_id, Network::grow_block_spread);
 
             for log_entry in logs {
                 println!("Received event {log_entry:?}",);
-                mutate_network_state(network_id, |s| s.record_log_to_process(&log_entry));
-            }
-
-            if read_network_state(network_id, Network::has_logs_to_process) {
-                println!("Found logs to process",);
-                let last_block_number_clone = last_block_number.clone();
-
-                // Process logs in a separate task to avoid blocking the current task
-                ic_cdk_timers::set_timer(Duration::from_secs(0), move || {
-                    ic_cdk::spawn(async move {
-                        process_logs(network_id).await;
-                        mutate_network_state(network_id, |s| {
-                            let n = s.last_processed_block_number.clone().unwrap_or(Nat::from(0u32));
-                            if n < last_block_number_clone {
-                                s.last_processed_block_number = Some(last_block_number_clone)
-                            }
-                        });
-                    })
-                });
+                mutate_network_state(network_id, |s| s.record_pending_log(&log_entry));
             }
 
             mutate_network_state(network_id, |s| s.last_scraped_block_number = last_block_number.clone());
@@ -174,19 +147,45 @@ async fn scrape_eth_logs_range_inclusive(network_id: u32, from: &Nat, to: &Nat)
     }
 }
 
-pub async fn scrape_eth_logs_on_all_networks() {
-    let network_ids = read_state(|s| s.networks.keys().cloned().collect::<Vec<u32>>());
-    for network_id in network_ids {
-        scrape_eth_logs(network_id).await;
+/// Processes the confirmed logs, then marks the blocks up to `block_number` as processed.
+fn schedule_log_processing(network_id: u32, block_number: Nat) {
+    if !read_network_state(network_id, Network::has_logs_to_process) {
+        return;
     }
+    println!("Found logs to process",);
+
+    // Process logs in a separate task to avoid blocking the current task
+    ic_cdk_timers::set_timer(Duration::from_secs(0), move || {
+        ic_cdk::spawn(async move {
+            process_logs(network_id).await;
+            if pause::check(PausableOperation::DepositCrediting, Some(network_id)).is_err() {
+                return;
+            }
+            mutate_network_state(network_id, |s| {
+                let n = s.last_processed_block_number.clone().unwrap_or(Nat::from(0u32));
+                if n < block_number {
+                    s.last_processed_block_number = Some(block_number)
+                }
+            });
+        })
+    });
 }
 
 pub async fn scrape_eth_logs(network_id: u32) {
-    let _guard = match TimerGuard::new(TaskType::ScrapeLogs) {
+    let _guard = match TimerGuard::new(TaskType::ScrapeLogs, network_id) {
         Ok(guard) => guard,
-        Err(_) => return,
+        Err(TimerGuardError::AlreadyProcessing) => return,
+        Err(e) => {
+            println!("[scrape_eth_logs]: skipping scraping ETH logs on network {network_id}: {e:?}");
+            return;
+        }
     };
 
+    if let Err(e) = pause::check(PausableOperation::LogScraping, Some(network_id)) {
+        println!("[scrape_eth_logs]: skipping scraping ETH logs: {e}");
+        return;
+    }
+
     let last_block_number = match update_last_observed_block_number(network_id).await {
         Ok(Some(block_number)) => block_number,
         Ok(None) => {
@@ -203,18 +202,105 @@ pub async fn scrape_eth_logs(network_id: u32) {
         }
     };
 
+    // Blocks past the block tag are scraped too, their deposits stay pending until the tag reaches them
+    let head = if read_network_state(network_id, |s| matches!(s.block_tag, BlockTag::Latest)) {
+        last_block_number
+    } else {
+        match get_block_by_number(network_id, BlockTag::Latest).await {
+            Ok(latest) => max(latest.number, last_block_number),
+            Err(e) => {
+                println!("[scrape_eth_logs]: failed to get the latest block number: {e:?}");
+                last_block_number
+            }
+        }
+    };
+    mutate_network_state(network_id, |s| s.latest_block_number = Some(head.clone()));
+
+    let reorg_safe = read_network_state(network_id, Network::scrapes_unconfirmed_blocks);
+    if reorg_safe {
+        if let Err(e) = check_for_reorg(network_id, &head).await {
+            println!("[scrape_eth_logs]: skipping scraping ETH logs: failed to check for a reorg: {e:?}");
+            return;
+        }
+    }
+
     let mut last_scraped_block_number = read_network_state(network_id, |s| s.last_scraped_block_number.clone());
 
-    while last_scraped_block_number < last_block_number {
+    while last_scraped_block_number < head {
         let next_block_to_query = last_scraped_block_number.add(Nat::from(1u32));
         last_scraped_block_number =
-            match scrape_eth_logs_range_inclusive(network_id, &next_block_to_query, &last_block_number).await {
+            match scrape_eth_logs_range_inclusive(network_id, &next_block_to_query, &head).await {
                 Some(last_scraped_block_number) => last_scraped_block_number,
                 None => {
                     return;
                 }
             };
     }
+
+    let confirmed = mutate_network_state(network_id, |s| {
+        s.confirm_pending_logs(&head);
+        s.confirmed_block_number(&head)
+    });
+    schedule_log_processing(network_id, confirmed);
+
+    if reorg_safe {
+        // The next scrape checks that the chain still builds on this block
+        match get_block_by_number(network_id, BlockTag::Number(head)).await {
+            Ok(block) => mutate_network_state(network_id, |s| {
+                s.block_hashes.insert(block.number, block.hash);
+            }),
+            Err(e) => println!("[scrape_eth_logs]: failed to record the hash of the last scraped block: {e:?}"),
+        }
+    }
+}
+
+/// Detects a reorg by comparing the parent hash of the block following the last recorded one
+/// with the recorded hash. On a mismatch, walks back to the last recorded block that is still
+/// part of the chain and drops the pending logs scraped after it.
+async fn check_for_reorg(network_id: u32, head: &Nat) -> Result<(), GetLogsError> {
+    let recorded = read_network_state(network_id, |s| s.block_hashes.clone());
+    let Some((last_number, last_hash)) = recorded.last_key_value() else {
+        return Ok(());
+    };
+    if head <= last_number {
+        return Ok(());
+    }
+    let next = get_block_by_number(network_id, BlockTag::Number(last_number.clone().add(Nat::from(1u32)))).await?;
+    if next.parentHash == *last_hash {
+        return Ok(());
+    }
+    println!("Reorg detected on network {network_id}: block {last_number} is no longer {last_hash}");
+
+    // Without a match, the oldest recorded block is assumed final and takes the new hash
+    let mut ancestor = None;
+    for (number, hash) in recorded.iter().rev() {
+        let block = get_block_by_number(network_id, BlockTag::Number(number.clone())).await?;
+        let found = block.hash == *hash;
+        ancestor = Some((number.clone(), block.hash));
+        if found {
+            break;
+        }
+    }
+    let (number, hash) = ancestor.expect("BUG: no recorded block hashes");
+    let orphaned = mutate_network_state(network_id, |s| s.roll_back_to(number.clone(), hash));
+    println!("Rolled network {network_id} back to block {number}, dropping the pending logs {orphaned:?}");
+    Ok(())
+}
+
+async fn get_block_by_number(network_id: u32, block_tag: BlockTag) -> Result<Block, GetLogsError> {
+    let rpc_providers = read_network_state(network_id, |s| s.rpc_services.clone());
+
+    let cycles = 10_000_000_000;
+    let (result,) = EVM_RPC
+        .eth_get_block_by_number(rpc_providers, None, block_tag, cycles)
+        .await
+        .map_err(|e| GetLogsError::CallRejected(e.1))?;
+
+    match result {
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Ok(block)) => Ok(block),
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Err(e)) => Err(GetLogsError::RpcError(e)),
+        MultiGetBlockByNumberResult::Inconsistent(_) => Err(GetLogsError::InconsistentResults),
+    }
 }
 
 pub enum GetBlockNumberError {
@@ -249,6 +335,37 @@ async fn update_last_observed_block_number(network_id: u32) -> Result<Option<Nat
     }
 }
 
+impl GetLogsError {
+    /// Whether the provider rejected the range because of the number of blocks or the size of the response.
+    pub fn is_range_too_large(&self) -> bool {
+        match self {
+            GetLogsError::RpcError(RpcError::HttpOutcallError(e)) => e.is_response_too_large(),
+            GetLogsError::RpcError(RpcError::JsonRpcError(e)) => is_range_limit_error(e),
+            _ => false,
+        }
+    }
+}
+
+/// The JSON-RPC error codes and message prefixes with which providers reject a range of
+/// `eth_getLogs`. Codes are shared with unrelated errors, such as rate limits, so both must match.
+const RANGE_LIMIT_ERRORS: &[(i64, &str)] = &[
+    // Alchemy: "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range..."
+    (-32602, "log response size exceeded"),
+    // QuickNode: "eth_getLogs is limited to a 10,000 range"
+    (-32602, "eth_getlogs is limited to"),
+    // Infura: "query returned more than 10000 results"
+    (-32005, "query returned more than"),
+    // Geth based nodes: "exceed maximum block range: 5000"
+    (-32000, "exceed maximum block range"),
+];
+
+pub fn is_range_limit_error(error: &JsonRpcError) -> bool {
+    let message = error.message.to_lowercase();
+    RANGE_LIMIT_ERRORS
+        .iter()
+        .any(|(code, prefix)| error.code == *code && message.starts_with(prefix))
+}
+
 impl HttpOutcallError {
     pub fn is_response_too_large(&self) -> bool {
         match self {
@@ -261,3 +378,19 @@ impl HttpOutcallError {
 pub fn is_response_too_large(code: &RejectionCode, message: &str) -> bool {
     code == &RejectionCode::SysFatal && message.contains("size limit")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_range_limits_shrink_the_spread() {
+        let error = |code: i64, message: &str| JsonRpcError { code, message: message.to_string() };
+        assert!(is_range_limit_error(&error(-32005, "query returned more than 10000 results")));
+        assert!(is_range_limit_error(&error(-32602, "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range")));
+        // Rate limits share codes and wording with range limits
+        assert!(!is_range_limit_error(&error(-32005, "project ID request rate exceeded")));
+        assert!(!is_range_limit_error(&error(-32000, "more than 100 requests per second")));
+        assert!(!is_range_limit_error(&error(-32000, "query returned more than 10000 results")));
+    }
+}
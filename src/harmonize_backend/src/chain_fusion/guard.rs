@@ -4,20 +4,29 @@ use crate::chain_fusion::TaskType;
 #[derive(Debug, PartialEq, Eq)]
 pub enum TimerGuardError {
     AlreadyProcessing,
+    /// Too many networks are scraping logs already.
+    TooManyConcurrentScrapes,
 }
 
+/// Ensures a task runs at most once at a time on each network.
 #[derive(Debug, PartialEq, Eq)]
 pub struct TimerGuard {
     task: TaskType,
+    network_id: u32,
 }
 
 impl TimerGuard {
-    pub fn new(task: TaskType) -> Result<Self, TimerGuardError> {
+    pub fn new(task: TaskType, network_id: u32) -> Result<Self, TimerGuardError> {
         mutate_state(|s| {
-            if !s.active_tasks.insert(task) {
+            if s.active_tasks.contains(&(task, network_id)) {
                 return Err(TimerGuardError::AlreadyProcessing);
             }
-            Ok(Self { task })
+            let scrapes = s.active_tasks.iter().filter(|(task, _)| *task == TaskType::ScrapeLogs).count();
+            if task == TaskType::ScrapeLogs && scrapes >= s.max_concurrent_scrapes as usize {
+                return Err(TimerGuardError::TooManyConcurrentScrapes);
+            }
+            s.active_tasks.insert((task, network_id));
+            Ok(Self { task, network_id })
         })
     }
 }
@@ -25,7 +34,7 @@ impl TimerGuard {
 impl Drop for TimerGuard {
     fn drop(&mut self) {
         mutate_state(|s| {
-            s.active_tasks.remove(&self.task);
+            s.active_tasks.remove(&(self.task, self.network_id));
         });
     }
 }
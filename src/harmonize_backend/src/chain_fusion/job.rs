@@ -9,7 +9,7 @@ use crate::{
     chain_fusion::{
     evm_rpc::LogEntry,
     LogSource,
-}, state::{mutate_network_state, mutate_state}, types::H160Ext, wallet::{Erc20, Eth}};
+}, state::{mutate_network_state, mutate_state}, tokens::{credit_deposit, credit_token_deposit, HeldDeposit}, types::H160Ext, wallet::AssetId};
 
 
 // because we deploy the canister with topics only matching
@@ -26,21 +26,29 @@ use crate::{
 
 use events::*;
 
-pub fn handle_deposit_eth_event(network_id: u32, event: DepositEthEvent) {
+pub fn handle_deposit_eth_event(network_id: u32, source: LogSource, event: DepositEthEvent) {
     println!("Wallet {} deposited {} of eth currency on network {}", event.sender.to_repr(), event.amount, network_id);
     mutate_state(|s| {
-        if let Err(e) = s.wallets.credit::<Eth>(event.recipient, &network_id, event.amount) {
-            println!("Error crediting wallet: {:?}", e);
-        }
+        let asset = AssetId::Native { chain_id: network_id };
+        credit_deposit(s, event.recipient, network_id, asset, event.amount, source);
     })
 }
 
-pub fn handle_deposit_erc20_event(network_id: u32, event: DepositErc20Event) {
+pub fn handle_deposit_erc20_event(network_id: u32, source: LogSource, event: DepositErc20Event) {
     println!("Wallet {} deposited {} of {}/{} to {}", event.sender.to_repr(), event.amount, network_id, event.token.to_repr(), event.recipient);
     mutate_state(|s| {
-        if let Err(e) = s.wallets.credit::<Erc20>(event.recipient, &(network_id, event.token), event.amount) {
-            println!("Error crediting wallet: {:?}", e);
+        if !s.tokens.is_listed(network_id, event.token) {
+            println!("Token {} is not listed on network {}, holding the deposit", event.token.to_repr(), network_id);
+            s.tokens.hold(HeldDeposit {
+                recipient: event.recipient,
+                chain_id: network_id,
+                token: event.token.into(),
+                amount: event.amount.into(),
+                source,
+            });
+            return;
         }
+        credit_token_deposit(s, event.recipient, network_id, event.token, event.amount, source);
     })
 }
 
@@ -56,11 +64,11 @@ pub async fn handle_event(network_id: u32, event_source: LogSource, event: LogEn
     match event {
         Event::DepositEth(deposit_eth_event) => {
             println!("DepositEth event: {:?}", deposit_eth_event);
-            handle_deposit_eth_event(network_id, deposit_eth_event);
+            handle_deposit_eth_event(network_id, event_source, deposit_eth_event);
         },
         Event::DepositErc20(deposit_erc20_event) => {
             println!("DepositErc20 event: {:?}", deposit_erc20_event);
-            handle_deposit_erc20_event(network_id, deposit_erc20_event);
+            handle_deposit_erc20_event(network_id, event_source, deposit_erc20_event);
         },
     }
 }
@@ -77,6 +85,8 @@ pub enum ParseEventError {
     FailedToParseAddress,
     #[error("Failed to parse principal")]
     FailedToParsePrincipal,
+    #[error("Failed to parse subaccount")]
+    FailedToParseSubaccount,
 }
 
 pub fn parse_principal_from_topic(topic: &str) -> Result<Principal, ParseEventError> {
@@ -88,9 +98,8 @@ pub fn parse_principal_from_topic(topic: &str) -> Result<Principal, ParseEventEr
 }
 
 pub mod events {
-    use candid::Principal;
     use ethers_core::types::{H160, U256};
-    use crate::{chain_fusion::evm_rpc::LogEntry, chain_fusion::ecdsa::keccak256};
+    use crate::{chain_fusion::evm_rpc::LogEntry, chain_fusion::ecdsa::keccak256, wallet::{Account, Subaccount}};
 
     use super::{parse_address_from_topic, parse_principal_from_topic, ParseEventError};
 
@@ -100,16 +109,30 @@ pub mod events {
         DepositErc20(DepositErc20Event),
     }
 
+    impl Event {
+        /// The `topic0` of every event the canister handles.
+        pub fn topics() -> Vec<String> {
+            vec![
+                DepositEthEvent::topic(),
+                DepositEthEvent::subaccount_topic(),
+                DepositErc20Event::topic(),
+                DepositErc20Event::subaccount_topic(),
+            ]
+        }
+    }
+
     impl TryFrom<LogEntry> for Event {
         type Error = ParseEventError;
 
         fn try_from(entry: LogEntry) -> Result<Event, Self::Error> {
             match entry.topics[0].as_str() {
-                _topic0 if _topic0 == DepositEthEvent::topic().as_str() => {
+                _topic0 if _topic0 == DepositEthEvent::topic().as_str()
+                    || _topic0 == DepositEthEvent::subaccount_topic().as_str() => {
                     let event = DepositEthEvent::try_from(entry)?;
                     Ok(Event::DepositEth(event))
                 }
-                _topic0 if _topic0 == DepositErc20Event::topic().as_str() => {
+                _topic0 if _topic0 == DepositErc20Event::topic().as_str()
+                    || _topic0 == DepositErc20Event::subaccount_topic().as_str() => {
                     let event = DepositErc20Event::try_from(entry)?;
                     Ok(Event::DepositErc20(event))
                 }
@@ -118,10 +141,33 @@ pub mod events {
         }
     }
 
+    fn topic(signature: &str) -> String {
+        let topic = hex::encode(keccak256(signature.as_bytes()));
+        format!("0x{}", topic)
+    }
+
+    /// Parses the non-indexed data of a deposit event, which is either just the amount,
+    /// or the subaccount followed by the amount for the `*ToSubaccount` events.
+    fn parse_deposit_data(data: &str, with_subaccount: bool) -> Result<(Option<Subaccount>, U256), ParseEventError> {
+        if !with_subaccount {
+            let amount = U256::from_str_radix(data, 16)
+                .map_err(|_| ParseEventError::FailedToParseUint)?;
+            return Ok((None, amount));
+        }
+        let bytes = hex::decode(data.trim_start_matches("0x"))
+            .map_err(|_| ParseEventError::FailedToParseSubaccount)?;
+        if bytes.len() != 64 {
+            return Err(ParseEventError::FailedToParseSubaccount);
+        }
+        let mut subaccount = [0u8; 32];
+        subaccount.copy_from_slice(&bytes[..32]);
+        Ok((Some(subaccount), U256::from_big_endian(&bytes[32..])))
+    }
+
     #[derive(Debug, Clone)]
     pub struct DepositEthEvent {
         pub sender: H160,
-        pub recipient: Principal,
+        pub recipient: Account,
         pub amount: U256,
     }
 
@@ -130,8 +176,13 @@ pub mod events {
             "DepositEth(address,bytes32,uint256)"
         }
         pub fn topic() -> String {
-            let topic = hex::encode(keccak256(Self::signature().as_bytes()));
-            format!("0x{}", topic)
+            topic(Self::signature())
+        }
+        pub fn subaccount_signature() -> &'static str {
+            "DepositEthToSubaccount(address,bytes32,bytes32,uint256)"
+        }
+        pub fn subaccount_topic() -> String {
+            topic(Self::subaccount_signature())
         }
     }
 
@@ -142,18 +193,18 @@ pub mod events {
             if entry.topics.len() != 3 {
                 return Err(ParseEventError::InvalidTopics);
             }
+            let with_subaccount = entry.topics[0] == Self::subaccount_topic();
             let sender: H160 = parse_address_from_topic(&entry.topics[1])?;
-            let recipient: Principal = parse_principal_from_topic(&entry.topics[2])?;
-            let amount = U256::from_str_radix(&entry.data, 16)
-                .map_err(|_| ParseEventError::FailedToParseUint)?;
-            Ok(DepositEthEvent { sender, recipient, amount })
+            let owner = parse_principal_from_topic(&entry.topics[2])?;
+            let (subaccount, amount) = parse_deposit_data(&entry.data, with_subaccount)?;
+            Ok(DepositEthEvent { sender, recipient: Account::new(owner, subaccount), amount })
         }
     }
 
     #[derive(Debug, Clone)]
     pub struct DepositErc20Event {
         pub sender: H160,
-        pub recipient: Principal,
+        pub recipient: Account,
         pub token: H160,
         pub amount: U256,
     }
@@ -163,8 +214,13 @@ pub mod events {
             "DepositErc20(address,bytes32,address,uint256)"
         }
         pub fn topic() -> String {
-            let topic = hex::encode(keccak256(Self::signature().as_bytes()));
-            format!("0x{}", topic)
+            topic(Self::signature())
+        }
+        pub fn subaccount_signature() -> &'static str {
+            "DepositErc20ToSubaccount(address,bytes32,address,bytes32,uint256)"
+        }
+        pub fn subaccount_topic() -> String {
+            topic(Self::subaccount_signature())
         }
     }
 
@@ -175,12 +231,12 @@ pub mod events {
             if entry.topics.len() != 4 {
                 return Err(ParseEventError::InvalidTopics);
             }
+            let with_subaccount = entry.topics[0] == Self::subaccount_topic();
             let sender: H160 = parse_address_from_topic(&entry.topics[1])?;
-            let recipient: Principal = parse_principal_from_topic(&entry.topics[2])?;
+            let owner = parse_principal_from_topic(&entry.topics[2])?;
             let token: H160 = parse_address_from_topic(&entry.topics[3])?;
-            let amount = U256::from_str_radix(&entry.data, 16)
-                .map_err(|_| ParseEventError::FailedToParseUint)?;
-            Ok(DepositErc20Event { sender, recipient, token, amount })
+            let (subaccount, amount) = parse_deposit_data(&entry.data, with_subaccount)?;
+            Ok(DepositErc20Event { sender, recipient: Account::new(owner, subaccount), token, amount })
         }
     }
 }
@@ -1,14 +1,19 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat};
 use ethers_core::{types::{H160, U256}, utils::keccak256};
 use num::BigUint;
 
 use crate::{
     chain_fusion::{
         eth_send_raw_transaction::{create_sign_request, send_raw_transaction},
-        evm_rpc::{GetTransactionReceiptResult, MultiGetTransactionReceiptResult, SendRawTransactionStatus, TransactionReceipt, EVM_RPC},
+        evm_rpc::{
+            BlockTag, GetTransactionCountArgs, GetTransactionCountResult, GetTransactionReceiptResult,
+            MultiGetTransactionCountResult, MultiGetTransactionReceiptResult, SendRawTransactionStatus,
+            TransactionReceipt, EVM_RPC,
+        },
         evm_signer,
         fees::{self},
-    }, state::{mutate_state, read_network_state}, wallet::Eth
+    }, history::TransactionKind, settlement::GasHold, state::{mutate_state, read_network_state, read_state, State},
+    types::H160Ext, wallet::{Account, Eth, WalletError}
 };
 use ethers_core::abi::AbiEncode;
 use thiserror::Error;
@@ -32,6 +37,13 @@ pub enum TransactionError {
     FailedToGetReceipt(String),
     #[error("An RPC call failed")]
     RpcCallFailed,
+    /// The transaction was broadcast, but its receipt could not be retrieved.
+    #[error("Transaction {tx_hash} was sent but not confirmed: {reason}")]
+    Unconfirmed { tx_hash: String, reason: String },
+    #[error("Transaction {tx_hash} was reverted")]
+    Reverted { tx_hash: String },
+    #[error("Transaction {tx_hash} is not pending settlement")]
+    NotPending { tx_hash: String },
     #[error("Failed to get the fee history: {0}")]
     FeeHistoryError(#[from] fees::FeeHistoryError),
     #[error("Failed to sign the transaction: {0}")]
@@ -68,9 +80,14 @@ impl From<MultiGetTransactionReceiptResult> for Result<TransactionReceipt, Trans
     }
 }
 
-async fn send(network_id: u32, tx: String) -> Result<TransactionReceipt, TransactionError> {
-    let status = send_raw_transaction(network_id, tx.clone()).await;
-    println!("Placed transaction on network {}: {:?}", network_id, tx);
+/// Sends a transaction and waits for its receipt.
+///
+/// A transaction that was broadcast without a receipt is tracked until its
+/// holds are settled, see [`crate::settlement`].
+async fn send(tx: &PreparedTransaction) -> Result<TransactionReceipt, TransactionError> {
+    let network_id = tx.network_id;
+    let status = send_raw_transaction(network_id, tx.signed_tx.clone()).await;
+    println!("Placed transaction on network {}: {:?}", network_id, tx.signed_tx);
     let tx_hash = match status {
         Ok(Some(tx_hash)) => tx_hash,
         Ok(None) => {
@@ -84,24 +101,45 @@ async fn send(network_id: u32, tx: String) -> Result<TransactionReceipt, Transac
         s.nonce += U256::from(1);
     });
 
+    // From here on the transaction may be mined, so errors keep track of its hash
+    get_receipt(network_id, tx_hash.clone()).await.map_err(|e| {
+        mutate_state(|s| s.settlements.track(tx_hash.clone(), network_id, tx.nonce, ic_cdk::api::time()));
+        TransactionError::Unconfirmed { tx_hash, reason: e.to_string() }
+    })
+}
+
+pub async fn get_receipt(network_id: u32, tx_hash: String) -> Result<TransactionReceipt, TransactionError> {
     let rpc_providers = read_network_state(network_id, |s| s.rpc_services.clone());
-    let (result,) = EVM_RPC
-        .eth_get_transaction_receipt(rpc_providers, None, tx_hash)
-        .await
-        .map_err(|_| TransactionError::RpcCallFailed)?;
+    match EVM_RPC.eth_get_transaction_receipt(rpc_providers, None, tx_hash).await {
+        Ok((result,)) => result.into(),
+        Err(_e) => Err(TransactionError::RpcCallFailed),
+    }
+}
 
-    result.into()
+/// Returns the number of transactions of the canister's address that were mined on the network,
+/// which is the nonce of its next transaction.
+pub async fn get_mined_transaction_count(network_id: u32) -> Result<U256, TransactionError> {
+    let rpc_providers = read_network_state(network_id, |s| s.rpc_services.clone());
+    let address = read_state(|s| s.evm_address).ok_or(TransactionError::RpcCallFailed)?;
+    let args = GetTransactionCountArgs { address: address.to_repr(), block: BlockTag::Latest };
+    match EVM_RPC.eth_get_transaction_count(rpc_providers, None, args).await {
+        Ok((MultiGetTransactionCountResult::Consistent(GetTransactionCountResult::Ok(count)),)) => {
+            Ok(biguint_to_u256(count.0))
+        }
+        _ => Err(TransactionError::RpcCallFailed),
+    }
 }
 
 pub struct PreparedTransaction {
     pub network_id: u32,
     pub signed_tx: String,
+    pub nonce: U256,
     pub gas_limit: U256,
     pub fee_settings: fees::FeeSettings,
 }
 
 pub async fn send_with_gas_payment_by_safe(tx: PreparedTransaction) -> Result<TransactionReceipt, TransactionError> {
-    send(tx.network_id, tx.signed_tx.clone()).await
+    send(&tx).await
 }
 
 pub fn biguint_to_u256(n: BigUint) -> U256 {
@@ -122,48 +160,66 @@ mod tests {
     }
 }
 
-pub async fn send_with_gas_payment_by_user(sender: Principal, tx: PreparedTransaction) -> Result<TransactionReceipt, TransactionError> {
+pub async fn send_with_gas_payment_by_user(sender: Account, tx: PreparedTransaction) -> Result<TransactionReceipt, TransactionError> {
     let max_gas_cost = tx.gas_limit.checked_mul(tx.fee_settings.max_fee_per_gas.checked_add(tx.fee_settings.max_priority_fee_per_gas).expect("Fee settings are invalid")).expect("Fee settings are invalid");
 
-    // Reserve the gas cost from the user's account
-    let debit_result = mutate_state(|s| {
-        s.wallets.debit::<Eth>(sender, &tx.network_id, max_gas_cost)
+    // Hold the maximum gas cost in the user's account while the transaction is in flight
+    let hold_result = mutate_state(|s| {
+        s.wallets.hold::<Eth>(sender, &tx.network_id, max_gas_cost)?;
+        s.record_transaction([(sender, TransactionKind::GasReservation { chain_id: tx.network_id, amount: max_gas_cost.into() })]);
+        Ok::<(), WalletError>(())
     });
 
-    if let Err(_e) = debit_result {
+    if let Err(_e) = hold_result {
         return Err(TransactionError::InsufficientFunds);
     }
 
-    match send(tx.network_id, tx.signed_tx.clone()).await {
+    match send(&tx).await {
         Ok(receipt) => {
-            // Refund the user for the gas cost
+            // Charge the user for the gas used and release the rest
             let gas_used = biguint_to_u256(receipt.gasUsed.0.clone());
             let gas_cost = biguint_to_u256(receipt.effectiveGasPrice.0.clone()) * gas_used;
             assert!(gas_cost <= max_gas_cost, "Gas used exceeds the gas limit");
+            settle_gas(sender, tx.network_id, max_gas_cost, gas_cost);
 
-            let refund = max_gas_cost - gas_cost;
-            let credit_result = mutate_state(|s| {
-                s.wallets.credit::<Eth>(sender, &tx.network_id, refund)
-            });
-            if let Err(err) = credit_result {
-                println!("Error crediting wallet: {:?}", err);
+            // A reverted transaction still pays for its gas
+            if receipt.status == Nat::from(0u8) {
+                return Err(TransactionError::Reverted { tx_hash: receipt.transactionHash });
             }
             Ok(receipt)
         },
+        Err(TransactionError::Unconfirmed { tx_hash, reason }) => {
+            // The gas used is unknown until the receipt shows up, so the hold is kept until then
+            mutate_state(|s| s.settlements.hold_gas(&tx_hash, GasHold { payer: sender, max_gas_cost: max_gas_cost.into() }));
+            Err(TransactionError::Unconfirmed { tx_hash, reason })
+        },
         Err(e) => {
-            // The transaction failed, refund the user for the gas cost
-            // TODO: We should not refund the full amount in case of a revert
-            let credit_result = mutate_state(|s| {
-                s.wallets.credit::<Eth>(sender, &tx.network_id, max_gas_cost)
-            });
-            if let Err(err) = credit_result {
-                println!("Error crediting wallet: {:?}", err);
-            }
+            // The transaction was never broadcast, release the gas hold
+            settle_gas(sender, tx.network_id, max_gas_cost, U256::zero());
             Err(e)
         }
     }
 }
 
+/// Captures `gas_cost` out of the `max_gas_cost` held for a transaction and refunds the rest.
+pub fn charge_gas(s: &mut State, sender: Account, network_id: u32, max_gas_cost: U256, gas_cost: U256) -> Result<(), WalletError> {
+    let refund = max_gas_cost - gas_cost;
+    s.wallets.capture::<Eth>(sender, &network_id, gas_cost)?;
+    s.wallets.release::<Eth>(sender, &network_id, refund)?;
+    let payment = (!gas_cost.is_zero())
+        .then(|| (sender, TransactionKind::GasPayment { chain_id: network_id, amount: gas_cost.into() }));
+    let refund = (!refund.is_zero())
+        .then(|| (sender, TransactionKind::GasRefund { chain_id: network_id, amount: refund.into() }));
+    s.record_transaction(payment.into_iter().chain(refund));
+    Ok(())
+}
+
+fn settle_gas(sender: Account, network_id: u32, max_gas_cost: U256, gas_cost: U256) {
+    if let Err(err) = mutate_state(|s| charge_gas(s, sender, network_id, max_gas_cost, gas_cost)) {
+        println!("Error settling the gas hold: {:?}", err);
+    }
+}
+
 lazy_static! {
     static ref ETH_TRANSFER_GAS_LIMIT: U256 = U256::from(5000000);
 }
@@ -183,9 +239,11 @@ pub async fn transfer_eth_tx(network_id: u32, to: H160, amount: U256, gas_limit:
         None,
         fee_settings.clone(),
     ).await;
+    let nonce = request.nonce.expect("BUG: sign requests carry a nonce");
     Ok(PreparedTransaction {
         network_id,
         signed_tx: evm_signer::sign_transaction(request).await?,
+        nonce,
         gas_limit,
         fee_settings,
     })
@@ -193,7 +251,7 @@ pub async fn transfer_eth_tx(network_id: u32, to: H160, amount: U256, gas_limit:
 
 pub async fn transfer_eth(
     network_id: u32,
-    from: Principal,
+    from: Account,
     to: H160,
     amount: U256,
     gas_limit: Option<U256>,
@@ -239,9 +297,11 @@ pub async fn transfer_erc20_tx(
         Some(data),
         fee_settings.clone(),
     ).await;
+    let nonce = request.nonce.expect("BUG: sign requests carry a nonce");
     Ok(PreparedTransaction {
         network_id,
         signed_tx: evm_signer::sign_transaction(request).await?,
+        nonce,
         gas_limit,
         fee_settings,
     })
@@ -250,7 +310,7 @@ pub async fn transfer_erc20_tx(
 pub async fn transfer_erc20(
     network_id: u32,
     token: H160,
-    from: Principal,
+    from: Account,
     to: H160,
     amount: U256,
     gas_limit: Option<U256>,
@@ -9,16 +9,23 @@ pub mod eth_send_raw_transaction;
 pub mod ecdsa;
 
 use std::time::Duration;
-use eth_get_logs::scrape_eth_logs_on_all_networks;
+use eth_get_logs::scrape_eth_logs;
 use crate::{
     chain_fusion::evm_rpc::LogEntry,
-    state::{mutate_state, self},
+    settlement,
+    state::{mutate_state, read_state, self},
 };
-use candid::Nat;
+use candid::{CandidType, Deserialize, Nat};
 
 // pub const SCRAPING_LOGS_INTERVAL: Duration = Duration::from_secs(3 * 60);
 pub const SCRAPING_LOGS_INTERVAL: Duration = Duration::from_secs(30);
 
+/// How often the holds of transactions without a receipt are settled.
+pub const SETTLEMENT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many networks may scrape logs at the same time unless configured otherwise.
+pub const DEFAULT_MAX_CONCURRENT_SCRAPES: u32 = 4;
+
 pub fn setup_timers() {
     // as timers are synchronous, we need to spawn a new async task to get the public key
     ic_cdk_timers::set_timer(Duration::ZERO, || {
@@ -33,9 +40,23 @@ pub fn setup_timers() {
             });
         })
     });
-    // // Start scraping logs almost immediately after the install, then repeat with the interval.
-    ic_cdk_timers::set_timer(Duration::from_secs(10), || ic_cdk::spawn(scrape_eth_logs_on_all_networks()));
-    ic_cdk_timers::set_timer_interval(SCRAPING_LOGS_INTERVAL, || ic_cdk::spawn(scrape_eth_logs_on_all_networks()));
+    let network_ids = read_state(|s| s.networks.keys().cloned().collect::<Vec<u32>>());
+    for network_id in network_ids {
+        start_scraping(network_id);
+    }
+    ic_cdk_timers::set_timer_interval(SETTLEMENT_INTERVAL, || ic_cdk::spawn(settlement::settle_pending_transactions()));
+}
+
+/// Gives a network its own scraping timer, unless it already has one, so that a slow
+/// network does not hold up the others.
+pub fn start_scraping(network_id: u32) {
+    if read_state(|s| s.scraping_timers.contains_key(&network_id)) {
+        return;
+    }
+    // Start scraping logs almost immediately, then repeat with the interval.
+    ic_cdk_timers::set_timer(Duration::from_secs(10), move || ic_cdk::spawn(scrape_eth_logs(network_id)));
+    let timer = ic_cdk_timers::set_timer_interval(SCRAPING_LOGS_INTERVAL, move || ic_cdk::spawn(scrape_eth_logs(network_id)));
+    mutate_state(|s| s.scraping_timers.insert(network_id, timer));
 }
 
 // TODO: Move this to another module
@@ -62,7 +83,7 @@ impl LogEntry {
 
 /// A unique identifier of the event source: the source transaction hash and the log
 /// entry index.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LogSource {
     pub transaction_hash: String,
     pub log_index: Nat,
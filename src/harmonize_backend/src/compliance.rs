@@ -0,0 +1,181 @@
+//! Frozen principals and blocked withdrawal destinations.
+//!
+//! A frozen principal can neither send nor receive funds, and deposits aimed
+//! at it are quarantined until it is unfrozen. Withdrawals to a blocked
+//! address are rejected.
+
+use std::collections::BTreeSet;
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::types::{H160, U256};
+use thiserror::Error;
+
+use crate::{
+    access_control::{require_role, Role},
+    audit,
+    chain_fusion::LogSource,
+    pause::PausableOperation,
+    state::{mutate_state, read_state},
+    tokens::credit_deposit,
+    types::{H160t, U256t},
+    wallet::{Account, AssetId},
+};
+
+#[derive(Error, Debug, CandidType)]
+pub enum ComplianceError {
+    #[error("The account of {principal} is frozen")]
+    AccountFrozen { principal: Principal },
+    #[error("Withdrawals to {address} are blocked")]
+    DestinationBlocked { address: H160t },
+}
+
+/// A deposit aimed at a frozen principal, credited once the principal is unfrozen.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct QuarantinedDeposit {
+    pub recipient: Account,
+    /// The network the deposit was made on, `None` for deposits quarantined before it was recorded.
+    pub chain_id: Option<u32>,
+    pub asset: AssetId,
+    pub amount: U256t,
+    pub source: LogSource,
+}
+
+#[derive(Default)]
+pub struct Compliance {
+    pub frozen: BTreeSet<Principal>,
+    pub blocked: BTreeSet<H160>,
+    pub quarantined: Vec<QuarantinedDeposit>,
+}
+
+impl Compliance {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn is_frozen(&self, principal: &Principal) -> bool {
+        self.frozen.contains(principal)
+    }
+
+    /// Checks that neither side of a transfer is frozen.
+    pub fn check_transfer(&self, from: &Account, to: &Account) -> Result<(), ComplianceError> {
+        for principal in [from.owner, to.owner] {
+            if self.is_frozen(&principal) {
+                return Err(ComplianceError::AccountFrozen { principal });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_withdrawal(&self, from: &Account, to: H160) -> Result<(), ComplianceError> {
+        if self.is_frozen(&from.owner) {
+            return Err(ComplianceError::AccountFrozen { principal: from.owner });
+        }
+        if self.blocked.contains(&to) {
+            return Err(ComplianceError::DestinationBlocked { address: to.into() });
+        }
+        Ok(())
+    }
+
+    pub fn quarantine(&mut self, recipient: Account, chain_id: u32, asset: AssetId, amount: U256, source: LogSource) {
+        self.quarantined.push(QuarantinedDeposit { recipient, chain_id: Some(chain_id), asset, amount: amount.into(), source });
+    }
+
+    /// Removes and returns the quarantined deposits of `principal`.
+    pub fn take_quarantined(&mut self, principal: Principal) -> Vec<QuarantinedDeposit> {
+        let (taken, kept) = std::mem::take(&mut self.quarantined)
+            .into_iter()
+            .partition(|d| d.recipient.owner == principal);
+        self.quarantined = kept;
+        taken
+    }
+}
+
+pub fn check_transfer(from: &Account, to: &Account) -> Result<(), ComplianceError> {
+    read_state(|s| s.compliance.check_transfer(from, to))
+}
+
+pub fn check_withdrawal(from: &Account, to: H160) -> Result<(), ComplianceError> {
+    read_state(|s| s.compliance.check_withdrawal(from, to))
+}
+
+// Public API
+
+pub fn freeze_principal(principal: Principal) {
+    require_role(Role::Admin);
+    mutate_state(|s| s.compliance.frozen.insert(principal));
+    audit::record("freeze_principal", (principal,));
+}
+
+/// Unfreezes a principal and credits the deposits quarantined for it. Deposits made on
+/// a network where deposit crediting is paused stay quarantined until it is resumed
+/// and the principal is unfrozen again.
+pub fn unfreeze_principal(principal: Principal) {
+    require_role(Role::Admin);
+    mutate_state(|s| {
+        s.compliance.frozen.remove(&principal);
+        for deposit in s.compliance.take_quarantined(principal) {
+            let chain_id = deposit.chain_id.or(deposit.asset.chain_id());
+            if s.pauses.check(PausableOperation::DepositCrediting, chain_id).is_err() {
+                s.compliance.quarantined.push(deposit);
+                continue;
+            }
+            // The principal is no longer frozen, so the network is only needed for the pause check
+            let chain_id = chain_id.unwrap_or_default();
+            credit_deposit(s, deposit.recipient, chain_id, deposit.asset, deposit.amount.into(), deposit.source);
+        }
+    });
+    audit::record("unfreeze_principal", (principal,));
+}
+
+pub fn block_address(address: H160) {
+    require_role(Role::Admin);
+    mutate_state(|s| s.compliance.blocked.insert(address));
+    audit::record("block_address", (address,));
+}
+
+pub fn unblock_address(address: H160) {
+    require_role(Role::Admin);
+    mutate_state(|s| s.compliance.blocked.remove(&address));
+    audit::record("unblock_address", (address,));
+}
+
+pub fn get_frozen_principals() -> Vec<Principal> {
+    require_role(Role::Auditor);
+    read_state(|s| s.compliance.frozen.iter().copied().collect())
+}
+
+pub fn get_blocked_addresses() -> Vec<H160t> {
+    require_role(Role::Auditor);
+    read_state(|s| s.compliance.blocked.iter().map(|address| (*address).into()).collect())
+}
+
+pub fn get_quarantined_deposits() -> Vec<QuarantinedDeposit> {
+    require_role(Role::Auditor);
+    read_state(|s| s.compliance.quarantined.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frozen_accounts_and_blocked_destinations() {
+        let mut compliance = Compliance::new();
+        let (alice, bob) = (Principal::anonymous(), Principal::management_canister());
+        let (alice, bob) = (Account::new(alice, None), Account::new(bob, None));
+        let address = H160::repeat_byte(1);
+
+        compliance.frozen.insert(bob.owner);
+        assert!(matches!(
+            compliance.check_transfer(&alice, &bob),
+            Err(ComplianceError::AccountFrozen { principal }) if principal == bob.owner
+        ));
+        assert!(compliance.check_withdrawal(&alice, address).is_ok());
+        assert!(compliance.check_withdrawal(&bob, address).is_err());
+
+        compliance.blocked.insert(address);
+        assert!(matches!(
+            compliance.check_withdrawal(&alice, address),
+            Err(ComplianceError::DestinationBlocked { .. })
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+//! Deduplication of retried calls.
+//!
+//! A call that carries a `created_at_time` is remembered together with its
+//! caller, memo and arguments for [`TX_WINDOW_NANOS`]. A retry within that
+//! window gets the outcome of the original call instead of running it again.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::utils::keccak256;
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+
+use crate::{chain_fusion::job::safe::TransactionError, state::mutate_state, HarmonizeError};
+
+/// How long calls are remembered, in nanoseconds.
+pub const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// How long a call may stay in flight before its key is freed, in nanoseconds. A call that
+/// traps after an inter-canister call never settles its key, which would otherwise reject
+/// its retries for the whole window.
+pub const IN_FLIGHT_TIMEOUT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+/// How far the `created_at_time` of a call may be ahead of the canister time, in nanoseconds.
+pub const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+/// The maximum length of a memo, in bytes.
+pub const MAX_MEMO_LENGTH: usize = 32;
+
+#[derive(Error, Debug, CandidType)]
+pub enum DedupError {
+    #[error("The call is too old")]
+    TooOld,
+    #[error("The call was created in the future, the canister time is {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("A call with the same memo and creation time is still in flight")]
+    InFlight,
+    #[error("A different kind of call was made with the same memo and creation time")]
+    Conflict,
+    #[error("The memo is longer than {MAX_MEMO_LENGTH} bytes")]
+    MemoTooLong,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DedupKey {
+    pub caller: Principal,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: u64,
+    /// The hash of the arguments of the call, see [`fingerprint`].
+    pub fingerprint: [u8; 32],
+}
+
+/// Hashes the arguments of a call, so that calls with different arguments are
+/// never mistaken for retries of each other.
+pub fn fingerprint(args: &impl CandidType) -> [u8; 32] {
+    keccak256(candid::encode_one(args).expect("BUG: failed to encode the call arguments"))
+}
+
+/// The outcome of a successful call, returned again for its duplicates.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum Outcome {
+    /// The id of the recorded transaction.
+    Transaction { id: u64 },
+    /// The hash of the transaction sent to the network.
+    Withdrawal { tx_hash: String },
+    /// The hash of a transaction that was sent to the network and reverted.
+    Reverted { tx_hash: String },
+}
+
+impl Outcome {
+    pub fn into_transaction_id(self) -> Result<u64, DedupError> {
+        match self {
+            Outcome::Transaction { id } => Ok(id),
+            _ => Err(DedupError::Conflict),
+        }
+    }
+
+    pub fn into_tx_hash(self) -> Result<String, HarmonizeError> {
+        match self {
+            Outcome::Withdrawal { tx_hash } => Ok(tx_hash),
+            Outcome::Reverted { tx_hash } => Err(TransactionError::Reverted { tx_hash }.into()),
+            _ => Err(DedupError::Conflict.into()),
+        }
+    }
+
+    /// Returns the outcome to remember for the result of a call, or `None` if
+    /// the call failed before it had any effect and may be retried.
+    pub fn of(result: &Result<Outcome, HarmonizeError>) -> Option<Outcome> {
+        match result {
+            Ok(outcome) => Some(outcome.clone()),
+            Err(HarmonizeError::TransactionError(TransactionError::Unconfirmed { tx_hash, .. })) => {
+                Some(Outcome::Withdrawal { tx_hash: tx_hash.clone() })
+            }
+            Err(HarmonizeError::TransactionError(TransactionError::Reverted { tx_hash })) => {
+                Some(Outcome::Reverted { tx_hash: tx_hash.clone() })
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// A call in the window.
+#[derive(Debug, Clone)]
+pub enum Call {
+    /// The call was reserved at `since` and has not completed yet.
+    InFlight { since: u64 },
+    /// The call completed with the given outcome.
+    Completed(Outcome),
+}
+
+pub enum Reservation {
+    /// The call carries no `created_at_time` and is not deduplicated.
+    Untracked,
+    /// The call is new and its key is reserved until it completes.
+    New(DedupKey),
+    /// The call already completed with the given outcome.
+    Duplicate(Outcome),
+}
+
+#[derive(Default)]
+pub struct Deduplication {
+    /// Calls in the window.
+    pub calls: BTreeMap<DedupKey, Call>,
+}
+
+impl Deduplication {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Forgets the calls that fell out of the window.
+    pub fn prune(&mut self, now: u64) {
+        let oldest = now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+        self.calls.retain(|key, _| key.created_at_time >= oldest);
+    }
+
+    pub fn reserve(
+        &mut self,
+        caller: Principal,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        fingerprint: [u8; 32],
+        now: u64,
+    ) -> Result<Reservation, DedupError> {
+        if memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_LENGTH) {
+            return Err(DedupError::MemoTooLong);
+        }
+        let Some(created_at_time) = created_at_time else {
+            return Ok(Reservation::Untracked);
+        };
+        if created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+            return Err(DedupError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(DedupError::CreatedInFuture { ledger_time: now });
+        }
+        self.prune(now);
+
+        let key = DedupKey { caller, memo, created_at_time, fingerprint };
+        match self.calls.get(&key) {
+            Some(Call::Completed(outcome)) => Ok(Reservation::Duplicate(outcome.clone())),
+            Some(Call::InFlight { since }) if now < since.saturating_add(IN_FLIGHT_TIMEOUT_NANOS) => {
+                Err(DedupError::InFlight)
+            }
+            // The call is new, or it has been in flight for so long that it must have trapped
+            _ => {
+                self.calls.insert(key.clone(), Call::InFlight { since: now });
+                Ok(Reservation::New(key))
+            }
+        }
+    }
+
+    /// Records the outcome of a reserved call, or frees its key if there is none so that it can be retried.
+    pub fn settle(&mut self, key: &DedupKey, outcome: Option<Outcome>) {
+        match outcome {
+            Some(outcome) => {
+                if let Some(entry) = self.calls.get_mut(key) {
+                    *entry = Call::Completed(outcome);
+                }
+            }
+            None => {
+                self.calls.remove(key);
+            }
+        }
+    }
+}
+
+fn reserve(
+    caller: Principal,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+    args: &impl CandidType,
+) -> Result<Reservation, DedupError> {
+    let now = ic_cdk::api::time();
+    let fingerprint = fingerprint(args);
+    mutate_state(|s| s.dedup.reserve(caller, memo.map(ByteBuf::into_vec), created_at_time, fingerprint, now))
+}
+
+// Public API
+
+/// Runs `f` unless the same call with the same `args` was already made, in which case its outcome is returned.
+pub fn deduplicate(
+    caller: Principal,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+    args: &impl CandidType,
+    f: impl FnOnce() -> Result<Outcome, HarmonizeError>,
+) -> Result<Outcome, HarmonizeError> {
+    match reserve(caller, memo, created_at_time, args)? {
+        Reservation::Untracked => f(),
+        Reservation::Duplicate(outcome) => Ok(outcome),
+        Reservation::New(key) => {
+            let result = f();
+            mutate_state(|s| s.dedup.settle(&key, Outcome::of(&result)));
+            result
+        }
+    }
+}
+
+/// Like [`deduplicate`], for calls that make inter-canister calls.
+///
+/// The key stays reserved while `f` is in flight, so that retries are rejected
+/// instead of running concurrently. It is only freed again if `f` failed before
+/// sending a transaction, or after [`IN_FLIGHT_TIMEOUT_NANOS`] if `f` trapped.
+pub async fn deduplicate_async(
+    caller: Principal,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+    args: &impl CandidType,
+    f: impl Future<Output = Result<Outcome, HarmonizeError>>,
+) -> Result<Outcome, HarmonizeError> {
+    match reserve(caller, memo, created_at_time, args)? {
+        Reservation::Untracked => f.await,
+        Reservation::Duplicate(outcome) => Ok(outcome),
+        Reservation::New(key) => {
+            let result = f.await;
+            mutate_state(|s| s.dedup.settle(&key, Outcome::of(&result)));
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries_return_the_original_outcome() {
+        let mut dedup = Deduplication::new();
+        let caller = Principal::anonymous();
+        let now = TX_WINDOW_NANOS;
+
+        let Ok(Reservation::New(key)) = dedup.reserve(caller, None, Some(now), [0; 32], now) else {
+            panic!("the first call should be new");
+        };
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [0; 32], now), Err(DedupError::InFlight)));
+        dedup.settle(&key, Some(Outcome::Transaction { id: 7 }));
+        assert!(matches!(
+            dedup.reserve(caller, None, Some(now), [0; 32], now + 1),
+            Ok(Reservation::Duplicate(Outcome::Transaction { id: 7 }))
+        ));
+
+        // A different memo or different arguments make a different call
+        assert!(matches!(dedup.reserve(caller, Some(vec![1]), Some(now), [0; 32], now), Ok(Reservation::New(_))));
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [1; 32], now), Ok(Reservation::New(_))));
+        assert!(matches!(
+            dedup.reserve(caller, None, Some(now), [0; 32], now + TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1),
+            Err(DedupError::TooOld)
+        ));
+    }
+
+    #[test]
+    fn test_calls_in_flight_expire() {
+        let mut dedup = Deduplication::new();
+        let caller = Principal::anonymous();
+        let now = TX_WINDOW_NANOS;
+
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [0; 32], now), Ok(Reservation::New(_))));
+        let later = now + IN_FLIGHT_TIMEOUT_NANOS - 1;
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [0; 32], later), Err(DedupError::InFlight)));
+        // The first call never settled its key, so a retry after the timeout runs again
+        let Ok(Reservation::New(key)) = dedup.reserve(caller, None, Some(now), [0; 32], later + 1) else {
+            panic!("the expired call should be new");
+        };
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [0; 32], later + 2), Err(DedupError::InFlight)));
+        dedup.settle(&key, Some(Outcome::Transaction { id: 7 }));
+        assert!(matches!(
+            dedup.reserve(caller, None, Some(now), [0; 32], now + TX_WINDOW_NANOS),
+            Ok(Reservation::Duplicate(Outcome::Transaction { id: 7 }))
+        ));
+    }
+
+    #[test]
+    fn test_keys_are_only_freed_before_broadcast() {
+        let mut dedup = Deduplication::new();
+        let caller = Principal::anonymous();
+        let now = TX_WINDOW_NANOS;
+
+        let Ok(Reservation::New(key)) = dedup.reserve(caller, None, Some(now), [0; 32], now) else {
+            panic!("the first call should be new");
+        };
+        dedup.settle(&key, Outcome::of(&Err(TransactionError::NoTransaction.into())));
+        assert!(matches!(dedup.reserve(caller, None, Some(now), [0; 32], now), Ok(Reservation::New(_))));
+
+        let reverted = TransactionError::Reverted { tx_hash: "0x1".to_string() };
+        dedup.settle(&key, Outcome::of(&Err(reverted.into())));
+        let Ok(Reservation::Duplicate(outcome)) = dedup.reserve(caller, None, Some(now), [0; 32], now) else {
+            panic!("a reverted call should not be retried");
+        };
+        assert!(matches!(
+            outcome.into_tx_hash(),
+            Err(HarmonizeError::TransactionError(TransactionError::Reverted { .. }))
+        ));
+    }
+}
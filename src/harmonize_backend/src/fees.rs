@@ -0,0 +1,199 @@
+//! Protocol fees, collected into the wallet of the treasury.
+//!
+//! The owner sets a fee per operation and asset, either a flat amount or a
+//! share of the amount in basis points. Transfers and withdrawals pay the fee
+//! on top of the amount, while deposits have it deducted.
+
+use std::collections::BTreeMap;
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::types::U256;
+use thiserror::Error;
+
+use crate::{
+    history::TransactionKind,
+    state::{read_state, State},
+    types::U256t,
+    wallet::{Account, AssetId, WalletError},
+};
+
+/// The number of basis points in the whole amount.
+pub const MAX_BASIS_POINTS: u16 = 10_000;
+
+#[derive(Error, Debug, CandidType)]
+pub enum FeeError {
+    #[error("The fee can be at most {MAX_BASIS_POINTS} basis points")]
+    InvalidBasisPoints,
+}
+
+/// An operation that can be charged a fee.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Transfer,
+    Withdrawal,
+    Deposit,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fee {
+    /// The same amount whatever the amount of the operation.
+    Flat { amount: U256t },
+    /// A share of the amount of the operation, in hundredths of a percent.
+    BasisPoints { bps: u16 },
+}
+
+impl Fee {
+    /// The fee charged on an operation of `amount`, rounded down.
+    pub fn of(&self, amount: U256) -> U256 {
+        match self {
+            Fee::Flat { amount: fee } => fee.0,
+            Fee::BasisPoints { bps } => {
+                // Split the amount so that the multiplication cannot overflow
+                let (bps, whole) = (U256::from(*bps), U256::from(MAX_BASIS_POINTS));
+                amount / whole * bps + amount % whole * bps / whole
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct FeeRule {
+    pub operation: Operation,
+    pub asset: AssetId,
+    pub fee: Fee,
+}
+
+#[derive(Default)]
+pub struct FeeSchedule {
+    pub fees: BTreeMap<(Operation, AssetId), Fee>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the fee of `operation` on `asset`, or removes it if `fee` is `None`.
+    pub fn set(&mut self, operation: Operation, asset: AssetId, fee: Option<Fee>) -> Result<(), FeeError> {
+        match fee {
+            Some(Fee::BasisPoints { bps }) if bps > MAX_BASIS_POINTS => return Err(FeeError::InvalidBasisPoints),
+            Some(fee) => self.fees.insert((operation, asset), fee),
+            None => self.fees.remove(&(operation, asset)),
+        };
+        Ok(())
+    }
+
+    pub fn quote(&self, operation: Operation, asset: &AssetId, amount: U256) -> U256 {
+        self.fees
+            .get(&(operation, *asset))
+            .map_or(U256::zero(), |fee| fee.of(amount))
+    }
+
+    /// The fee charged on any amount, or `None` if the fee depends on the amount.
+    pub fn flat(&self, operation: Operation, asset: &AssetId) -> Option<U256> {
+        match self.fees.get(&(operation, *asset)) {
+            None => Some(U256::zero()),
+            Some(Fee::Flat { amount }) => Some(amount.0),
+            Some(Fee::BasisPoints { .. }) => None,
+        }
+    }
+
+    pub fn rules(&self) -> Vec<FeeRule> {
+        self.fees
+            .iter()
+            .map(|((operation, asset), fee)| FeeRule { operation: *operation, asset: *asset, fee: *fee })
+            .collect()
+    }
+}
+
+/// The history entries of a fee paid by `payer` to the treasury.
+pub fn fee_entries(payer: Account, treasury: Account, operation: Operation, asset: AssetId, fee: U256) -> Vec<(Account, TransactionKind)> {
+    if fee.is_zero() {
+        return vec![];
+    }
+    vec![
+        (payer, TransactionKind::Fee { operation, asset, amount: fee.into() }),
+        (treasury, TransactionKind::TransferIn { asset, amount: fee.into(), from: payer }),
+    ]
+}
+
+/// Applies `(from, to, asset, amount)` transfers together with their fees, either all
+/// of them or none, and records them as a single transaction whose id is returned.
+///
+/// A transfer is rejected if its sender cannot pay the amount or the fee on top of it.
+pub fn transfer_with_fees(s: &mut State, transfers: &[(Account, Account, AssetId, U256)]) -> Result<u64, Vec<(usize, WalletError)>> {
+    let treasury = s.treasury_account();
+    let mut legs = transfers.to_vec();
+    let mut fees = vec![];
+    for (index, (from, _, asset, amount)) in transfers.iter().enumerate() {
+        let fee = s.fees.quote(Operation::Transfer, asset, *amount);
+        if !fee.is_zero() {
+            legs.push((*from, treasury, *asset, fee));
+            fees.push(index);
+        }
+    }
+    s.wallets.batch_transfer(&legs).map_err(|rejected| {
+        // Report rejected fees against the transfer that pays them
+        let mut rejected: Vec<_> = rejected
+            .into_iter()
+            .map(|(index, error)| match index.checked_sub(transfers.len()) {
+                Some(fee) => (fees[fee], error),
+                None => (index, error),
+            })
+            .collect();
+        rejected.sort_by_key(|(index, _)| *index);
+        rejected.dedup_by_key(|(index, _)| *index);
+        rejected
+    })?;
+
+    let mut entries = vec![];
+    for (from, to, asset, amount) in transfers {
+        entries.extend(crate::history::transfer_entries(*from, *to, *asset, *amount));
+    }
+    for (from, treasury, asset, fee) in &legs[transfers.len()..] {
+        entries.extend(fee_entries(*from, *treasury, Operation::Transfer, *asset, *fee));
+    }
+    Ok(s.record_transaction(entries))
+}
+
+// Public API
+
+/// Returns the fee an operation of `amount` would be charged right now.
+pub fn quote_fee(operation: Operation, asset: AssetId, amount: U256) -> U256 {
+    read_state(|s| s.fees.quote(operation, &asset, amount))
+}
+
+pub fn get_fee_schedule() -> Vec<FeeRule> {
+    read_state(|s| s.fees.rules())
+}
+
+pub fn get_treasury() -> Principal {
+    read_state(|s| s.treasury)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basis_points_round_down_without_overflow() {
+        assert_eq!(Fee::BasisPoints { bps: 30 }.of(U256::from(10_000)), U256::from(30));
+        assert_eq!(Fee::BasisPoints { bps: 30 }.of(U256::from(333)), U256::from(0));
+        assert_eq!(Fee::BasisPoints { bps: MAX_BASIS_POINTS }.of(U256::MAX), U256::MAX);
+
+        let mut schedule = FeeSchedule::new();
+        let asset = AssetId::Native { chain_id: 1 };
+        assert!(matches!(
+            schedule.set(Operation::Transfer, asset, Some(Fee::BasisPoints { bps: MAX_BASIS_POINTS + 1 })),
+            Err(FeeError::InvalidBasisPoints)
+        ));
+        schedule.set(Operation::Transfer, asset, Some(Fee::Flat { amount: U256::from(5).into() })).unwrap();
+        assert_eq!(schedule.quote(Operation::Transfer, &asset, U256::from(1)), U256::from(5));
+        assert_eq!(schedule.quote(Operation::Withdrawal, &asset, U256::from(1)), U256::zero());
+
+        // Only flat fees are the same for every amount
+        assert_eq!(schedule.flat(Operation::Transfer, &asset), Some(U256::from(5)));
+        assert_eq!(schedule.flat(Operation::Withdrawal, &asset), Some(U256::zero()));
+        schedule.set(Operation::Transfer, asset, Some(Fee::BasisPoints { bps: 30 })).unwrap();
+        assert_eq!(schedule.flat(Operation::Transfer, &asset), None);
+    }
+}
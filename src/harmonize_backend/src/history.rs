@@ -0,0 +1,205 @@
+use std::{borrow::Cow, cell::RefCell};
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::types::U256;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+
+use crate::{
+    chain_fusion::LogSource,
+    fees::Operation,
+    state::read_state,
+    storage::{get_memory, Memory, HISTORY_COUNTS, HISTORY_TRANSACTIONS},
+    types::{H160t, U256t},
+    wallet::{Account, AssetId},
+};
+
+/// The maximum number of transactions returned by a single page.
+pub const MAX_TRANSACTIONS_PER_PAGE: u64 = 100;
+
+/// A change to the balance of a wallet.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum TransactionKind {
+    Deposit { asset: AssetId, amount: U256t, source: LogSource },
+    TransferIn { asset: AssetId, amount: U256t, from: Account },
+    TransferOut { asset: AssetId, amount: U256t, to: Account },
+    Withdrawal { asset: AssetId, amount: U256t, to: H160t, tx_hash: String },
+    /// A protocol fee paid to the treasury.
+    Fee { operation: Operation, asset: AssetId, amount: U256t },
+    /// The gas paid for a transaction sent on behalf of the wallet.
+    GasPayment { chain_id: u32, amount: U256t },
+    /// The maximum gas cost of a transaction, held until the transaction is mined or dropped.
+    GasReservation { chain_id: u32, amount: U256t },
+    /// The part of a gas reservation that was not paid.
+    GasRefund { chain_id: u32, amount: U256t },
+    /// The amount and fee of a withdrawal, held until its transaction is mined or dropped.
+    WithdrawalReservation { asset: AssetId, amount: U256t },
+    /// A withdrawal reservation given back because the withdrawal did not happen.
+    WithdrawalRefund { asset: AssetId, amount: U256t },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    /// The id of the transaction. Both sides of a transfer share the same id.
+    pub id: u64,
+    /// Nanoseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub kind: TransactionKind,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TransactionPage {
+    /// The total number of transactions of the wallet.
+    pub total: u64,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Identifies the `index`-th transaction of a wallet. The encoding sorts the
+/// transactions of a wallet together, oldest first.
+struct HistoryKey {
+    wallet: Account,
+    index: u64,
+}
+
+impl HistoryKey {
+    /// The length of the principal, the principal padded to 29 bytes, whether there
+    /// is a subaccount and the subaccount.
+    const WALLET_SIZE: usize = 1 + 29 + 1 + 32;
+}
+
+impl Storable for HistoryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let owner = self.wallet.owner.as_slice();
+        let mut bytes = vec![0; Self::WALLET_SIZE + 8];
+        bytes[0] = owner.len() as u8;
+        bytes[1..1 + owner.len()].copy_from_slice(owner);
+        if let Some(subaccount) = self.wallet.subaccount {
+            bytes[Self::WALLET_SIZE - 33] = 1;
+            bytes[Self::WALLET_SIZE - 32..Self::WALLET_SIZE].copy_from_slice(&subaccount);
+        }
+        bytes[Self::WALLET_SIZE..].copy_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner = Principal::from_slice(&bytes[1..1 + bytes[0] as usize]);
+        let subaccount = (bytes[Self::WALLET_SIZE - 33] == 1)
+            .then(|| bytes[Self::WALLET_SIZE - 32..Self::WALLET_SIZE].try_into().expect("BUG: invalid subaccount"));
+        let index = u64::from_be_bytes(bytes[Self::WALLET_SIZE..].try_into().expect("BUG: invalid index"));
+        HistoryKey { wallet: Account { owner, subaccount }, index }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: (Self::WALLET_SIZE + 8) as u32, is_fixed_size: true };
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("BUG: failed to encode a transaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("BUG: failed to decode a transaction")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    /// The transactions of every wallet, which live in their own stable memory since
+    /// they grow without bound.
+    static TRANSACTIONS: RefCell<StableBTreeMap<HistoryKey, Transaction, Memory>> = RefCell::new(
+        StableBTreeMap::init(get_memory(HISTORY_TRANSACTIONS))
+    );
+    /// The number of transactions of every wallet, keyed by the wallet with an index of zero.
+    static TRANSACTION_COUNTS: RefCell<StableBTreeMap<HistoryKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(get_memory(HISTORY_COUNTS))
+    );
+}
+
+fn count(wallet: Account) -> u64 {
+    TRANSACTION_COUNTS.with(|counts| counts.borrow().get(&HistoryKey { wallet, index: 0 }).unwrap_or_default())
+}
+
+/// Appends a transaction to the history of a wallet.
+fn append(wallet: Account, transaction: Transaction) {
+    let index = count(wallet);
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().insert(HistoryKey { wallet, index }, transaction));
+    TRANSACTION_COUNTS.with(|counts| counts.borrow_mut().insert(HistoryKey { wallet, index: 0 }, index + 1));
+}
+
+/// Assigns the ids of the transactions, whose entries are kept in stable memory.
+#[derive(Default)]
+pub struct History {
+    pub next_id: u64,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records a transaction touching one or more wallets and returns its id.
+    pub fn record(&mut self, timestamp: u64, entries: impl IntoIterator<Item=(Account, TransactionKind)>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        for (wallet, kind) in entries {
+            append(wallet, Transaction { id, timestamp, kind });
+        }
+        id
+    }
+
+    /// Appends transactions restored from a snapshot that held the whole history.
+    pub fn import(&mut self, wallet: Account, transactions: Vec<Transaction>) {
+        for transaction in transactions {
+            append(wallet, transaction);
+        }
+    }
+
+    /// Returns up to `limit` transactions of `wallet`, oldest first, starting at index `start`.
+    pub fn page(&self, wallet: &Account, start: u64, limit: u64) -> TransactionPage {
+        let wallet = *wallet;
+        let total = count(wallet);
+        let end = start.saturating_add(limit.min(MAX_TRANSACTIONS_PER_PAGE)).min(total);
+        TransactionPage {
+            total,
+            transactions: TRANSACTIONS.with(|transactions| {
+                let transactions = transactions.borrow();
+                (start..end).filter_map(|index| transactions.get(&HistoryKey { wallet, index })).collect()
+            }),
+        }
+    }
+}
+
+/// The history entries of an internal transfer, one for each side.
+pub fn transfer_entries(from: Account, to: Account, asset: AssetId, amount: U256) -> [(Account, TransactionKind); 2] {
+    [
+        (from, TransactionKind::TransferOut { asset, amount: amount.into(), to }),
+        (to, TransactionKind::TransferIn { asset, amount: amount.into(), from }),
+    ]
+}
+
+// Public API
+
+pub fn get_transactions(wallet: Account, start: u64, limit: u64) -> TransactionPage {
+    read_state(|s| s.history.page(&wallet, start, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transactions_are_paged_per_wallet() {
+        let mut history = History::new();
+        let (a, b) = (Account::new(Principal::anonymous(), None), Account::new(Principal::anonymous(), Some([1; 32])));
+        let asset = AssetId::Native { chain_id: 1 };
+        for _ in 0..3 {
+            history.record(0, transfer_entries(a, b, asset, U256::one()));
+        }
+        history.record(0, [(b, TransactionKind::GasPayment { chain_id: 1, amount: U256::one().into() })]);
+
+        let page = history.page(&b, 1, 10);
+        assert_eq!(page.total, 4);
+        assert_eq!(page.transactions.iter().map(|t| t.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(history.page(&a, 0, 2).transactions.len(), 2);
+        assert!(history.page(&a, 3, 10).transactions.is_empty());
+    }
+}
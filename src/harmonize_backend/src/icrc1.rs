@@ -0,0 +1,160 @@
+//! An ICRC-1 view on the virtual wallets.
+//!
+//! Every [`AssetId`] is exposed as its own ledger, so all methods take the
+//! ledger id as their first argument.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    dedup::{self, DedupError, Outcome, Reservation},
+    compliance,
+    fees::{transfer_with_fees, Operation},
+    pause::{self, PausableOperation},
+    state::{mutate_state, read_state},
+    types::{H160Ext, NatExt, U256Ext},
+    wallet::{balances::BalanceError, first_rejection, Account, AssetId, Subaccount, WalletError},
+};
+
+/// The number of decimals of the native currency of every supported network.
+pub const NATIVE_DECIMALS: u8 = 18;
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub created_at_time: Option<u64>,
+    pub memo: Option<ByteBuf>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+impl TransferError {
+    fn generic(error_code: u64, message: impl Into<String>) -> Self {
+        TransferError::GenericError {
+            error_code: Nat::from(error_code),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(ByteBuf),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+pub fn supported_standards() -> Vec<StandardRecord> {
+    vec![StandardRecord {
+        name: "ICRC-1".to_string(),
+        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+    }]
+}
+
+/// Returns the metadata of a ledger, and rejects ledgers whose symbol and decimals are unknown.
+pub fn metadata(ledger: AssetId) -> Vec<(String, MetadataValue)> {
+    let (name, symbol, decimals) = match ledger {
+        AssetId::Native { chain_id } => (format!("Ether ({})", chain_id), "ETH".to_string(), NATIVE_DECIMALS),
+        AssetId::Erc20 { chain_id, token } => match read_state(|s| s.tokens.get(chain_id, token.0).cloned()) {
+            Some(listed) => (listed.name, listed.symbol, listed.decimals),
+            None => ic_cdk::trap(&format!("The token {} is not listed on network {}.", token.0.to_repr(), chain_id)),
+        },
+        AssetId::Canonical { id } => match read_state(|s| s.canonical_assets.get(id).cloned()) {
+            Some(asset) => (asset.name, asset.symbol, asset.decimals),
+            None => ic_cdk::trap(&format!("The canonical asset {} does not exist.", id)),
+        },
+    };
+    let mut metadata = vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(name)),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(symbol)),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(decimals))),
+    ];
+    // Only flat fees can be advertised, fees in basis points depend on the amount
+    if let Some(fee) = read_state(|s| s.fees.flat(Operation::Transfer, &ledger)) {
+        metadata.push(("icrc1:fee".to_string(), MetadataValue::Nat(fee.to_nat())));
+    }
+    metadata
+}
+
+pub fn total_supply(ledger: AssetId) -> Nat {
+    read_state(|s| s.wallets.total_balance(&ledger)).to_nat()
+}
+
+pub fn balance_of(ledger: AssetId, account: Account) -> Nat {
+    let account = account.normalized();
+    read_state(|s| s.wallets.get_asset_balance(account, &ledger).cloned().unwrap_or_default()).to_nat()
+}
+
+pub fn transfer(caller: Principal, ledger: AssetId, arg: TransferArg) -> Result<Nat, TransferError> {
+    let from = Account::new(caller, arg.from_subaccount);
+    let to = arg.to.normalized();
+    let amount = arg
+        .amount
+        .try_to_u256()
+        .ok_or_else(|| TransferError::generic(0, "Amount does not fit into 256 bits"))?;
+    let expected_fee = read_state(|s| s.fees.quote(Operation::Transfer, &ledger, amount)).to_nat();
+    if arg.fee.is_some_and(|fee| fee != expected_fee) {
+        return Err(TransferError::BadFee { expected_fee });
+    }
+
+    if pause::check(PausableOperation::Transfers, ledger.chain_id()).is_err() {
+        return Err(TransferError::TemporarilyUnavailable);
+    }
+    if let Err(e) = compliance::check_transfer(&from, &to) {
+        return Err(TransferError::generic(4, e.to_string()));
+    }
+
+    let now = ic_cdk::api::time();
+    let fingerprint = dedup::fingerprint(&(&ledger, &arg));
+    let memo = arg.memo.map(ByteBuf::into_vec);
+    let key = match mutate_state(|s| s.dedup.reserve(caller, memo, arg.created_at_time, fingerprint, now)) {
+        Ok(Reservation::Untracked) => None,
+        Ok(Reservation::New(key)) => Some(key),
+        Ok(Reservation::Duplicate(Outcome::Transaction { id })) => {
+            return Err(TransferError::Duplicate { duplicate_of: Nat::from(id) })
+        }
+        Ok(Reservation::Duplicate(_)) | Err(DedupError::Conflict) => {
+            return Err(TransferError::generic(2, DedupError::Conflict.to_string()))
+        }
+        Err(DedupError::TooOld) => return Err(TransferError::TooOld),
+        Err(DedupError::CreatedInFuture { ledger_time }) => return Err(TransferError::CreatedInFuture { ledger_time }),
+        Err(DedupError::InFlight) => return Err(TransferError::TemporarilyUnavailable),
+        Err(e @ DedupError::MemoTooLong) => return Err(TransferError::generic(3, e.to_string())),
+    };
+
+    mutate_state(|s| {
+        let result = transfer_with_fees(s, &[(from, to, ledger, amount)])
+            .map_err(|rejected| match first_rejection(rejected) {
+                WalletError::NotFound | WalletError::BalanceError(BalanceError::InsufficientBalance) => {
+                    TransferError::InsufficientFunds {
+                        balance: s.wallets.get_asset_balance(from, &ledger).cloned().unwrap_or_default().to_nat(),
+                    }
+                }
+                e => TransferError::generic(1, e.to_string()),
+            });
+        if let Some(key) = key {
+            s.dedup.settle(&key, result.as_ref().ok().map(|id| Outcome::Transaction { id: *id }));
+        }
+        result.map(Nat::from)
+    })
+}
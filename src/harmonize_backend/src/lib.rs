@@ -3,13 +3,39 @@ pub mod access_control;
 pub mod wallet;
 pub mod types;
 pub mod state;
+pub mod storage;
+pub mod icrc1;
+pub mod history;
+pub mod tokens;
+pub mod dedup;
+pub mod canonical;
+pub mod fees;
+pub mod limits;
+pub mod pause;
+pub mod timelock;
+pub mod compliance;
+pub mod audit;
+pub mod settlement;
 pub mod declarations;
 
 use candid::{CandidType, Nat, Principal};
+use serde_bytes::ByteBuf;
 use thiserror::Error;
-use wallet::WalletError;
-use access_control::SignInError;
-use state::{read_state, Init, NetworkMut};
+use wallet::{Account, Allowance, AssetId, Balance, BatchTransferError, ChainBalances, Subaccount, TransferLeg, WalletError};
+use access_control::{Role, RoleError, SignInError};
+use canonical::{CanonicalAsset, CanonicalAssetError};
+use dedup::{DedupError, Outcome};
+use fees::{Fee, FeeError, FeeRule, Operation};
+use limits::{LimitScope, LimitUsage, RateLimitError, WithdrawalLimit};
+use pause::{PausableOperation, PauseError, PauseFlag};
+use timelock::{NetworkProposal, TimelockError};
+use compliance::{ComplianceError, QuarantinedDeposit};
+use audit::AuditPage;
+use settlement::{PendingTransaction, Settlement};
+use history::TransactionPage;
+use icrc1::{MetadataValue, StandardRecord, TransferArg, TransferError};
+use state::{read_state, Init, NetworkMut, NetworkStatus, PendingDeposit};
+use tokens::{HeldDeposit, TokenError, TokenInfo, TokenMetadata};
 use types::{H160t, U256t};
 
 #[derive(Error, Debug, CandidType)]
@@ -18,8 +44,28 @@ pub enum HarmonizeError {
     WalletError(#[from] WalletError),
     #[error("Sign in: {0}")]
     SignInError(#[from] SignInError),
+    #[error("Role: {0}")]
+    RoleError(#[from] RoleError),
     #[error("Transaction: {0}")]
     TransactionError(#[from] chain_fusion::job::safe::TransactionError),
+    #[error("Token: {0}")]
+    TokenError(#[from] TokenError),
+    #[error("Batch transfer: {0}")]
+    BatchTransferError(#[from] BatchTransferError),
+    #[error("Deduplication: {0}")]
+    DedupError(#[from] DedupError),
+    #[error("Canonical asset: {0}")]
+    CanonicalAssetError(#[from] CanonicalAssetError),
+    #[error("Fee: {0}")]
+    FeeError(#[from] FeeError),
+    #[error("Rate limit: {0}")]
+    RateLimitError(#[from] RateLimitError),
+    #[error("Paused: {0}")]
+    PauseError(#[from] PauseError),
+    #[error("Timelock: {0}")]
+    TimelockError(#[from] TimelockError),
+    #[error("Compliance: {0}")]
+    ComplianceError(#[from] ComplianceError),
 }
 
 #[ic_cdk::init]
@@ -29,6 +75,17 @@ fn init(args: Init) {
     chain_fusion::setup_timers();
 }
 
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    storage::save_state();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<Init>) {
+    storage::restore_state(args);
+    chain_fusion::setup_timers();
+}
+
 /*
  * Canister Settings
  */
@@ -38,9 +95,74 @@ fn set_owner(new_owner: Principal) {
     state::set_owner(new_owner);
 }
 
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) {
+    access_control::grant_role(principal, role);
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), HarmonizeError> {
+    access_control::revoke_role(principal, role)
+}
+
+#[ic_cdk::query]
+fn get_roles(principal: Principal) -> Vec<Role> {
+    access_control::get_roles(principal)
+}
+
+#[ic_cdk::query]
+fn get_role_holders() -> Vec<(Principal, Vec<Role>)> {
+    access_control::get_role_holders()
+}
+
+#[ic_cdk::query]
+fn get_audit_log(start: u64, limit: u64) -> AuditPage {
+    audit::get_audit_log(start, limit)
+}
+
+#[ic_cdk::update]
+fn set_treasury(treasury: Principal) {
+    state::set_treasury(treasury);
+}
+
+#[ic_cdk::query]
+fn get_treasury() -> Principal {
+    fees::get_treasury()
+}
+
+#[ic_cdk::update]
+fn set_fee(operation: Operation, asset: AssetId, fee: Option<Fee>) -> Result<(), HarmonizeError> {
+    state::set_fee(operation, asset, fee)
+}
+
+#[ic_cdk::query]
+fn get_fee_schedule() -> Vec<FeeRule> {
+    fees::get_fee_schedule()
+}
+
+#[ic_cdk::query]
+fn quote_fee(operation: Operation, asset: AssetId, amount: U256t) -> U256t {
+    fees::quote_fee(operation, asset, amount.into()).into()
+}
+
+#[ic_cdk::update]
+fn set_withdrawal_limit(asset: AssetId, scope: LimitScope, limit: Option<WithdrawalLimit>) -> Result<(), HarmonizeError> {
+    limits::set_withdrawal_limit(asset, scope, limit)
+}
+
+#[ic_cdk::query]
+fn get_withdrawal_limits(user: Principal) -> Vec<LimitUsage> {
+    limits::get_withdrawal_limits(user)
+}
+
+#[ic_cdk::update]
+fn set_paused(operation: PausableOperation, chain_id: Option<u32>, paused: bool) {
+    pause::set_paused(operation, chain_id, paused);
+}
+
 #[ic_cdk::query]
-fn get_owner() -> Principal {
-    state::get_owner()
+fn get_paused() -> Vec<PauseFlag> {
+    pause::get_paused()
 }
 
 #[ic_cdk::update]
@@ -48,6 +170,41 @@ fn set_network_config(chain_id: u32, network_mut: NetworkMut) {
     state::set_network_config(chain_id, network_mut);
 }
 
+#[ic_cdk::update]
+fn propose_network_config(chain_id: u32, network_mut: NetworkMut) -> Result<u64, HarmonizeError> {
+    timelock::propose_network_config(chain_id, network_mut)
+}
+
+#[ic_cdk::update]
+fn cancel_network_config(id: u64) -> Result<(), HarmonizeError> {
+    timelock::cancel_network_config(id)
+}
+
+#[ic_cdk::update]
+fn execute_network_config(id: u64) -> Result<(), HarmonizeError> {
+    timelock::execute_network_config(id)
+}
+
+#[ic_cdk::update]
+fn set_max_concurrent_scrapes(max_concurrent_scrapes: u32) {
+    state::set_max_concurrent_scrapes(max_concurrent_scrapes);
+}
+
+#[ic_cdk::query]
+fn get_max_concurrent_scrapes() -> u32 {
+    state::get_max_concurrent_scrapes()
+}
+
+#[ic_cdk::update]
+fn set_network_config_delay(delay_nanos: u64) {
+    timelock::set_network_config_delay(delay_nanos);
+}
+
+#[ic_cdk::query]
+fn get_network_proposals() -> Vec<NetworkProposal> {
+    timelock::get_network_proposals()
+}
+
 #[ic_cdk::query]
 fn get_ethereum_address() -> H160t {
     state::get_ethereum_address().into()
@@ -58,11 +215,99 @@ fn get_endpoint_address(chain_id: u32) -> H160t {
     state::get_endpoint_address(chain_id).into()
 }
 
+#[ic_cdk::query]
+fn get_network_status(chain_id: u32) -> NetworkStatus {
+    state::get_network_status(chain_id)
+}
+
 #[ic_cdk::query]
 fn get_last_processed_block(chain_id: u32) -> Nat {
     state::get_last_processed_block(chain_id)
 }
 
+/*
+ * Compliance
+ */
+
+#[ic_cdk::update]
+fn freeze_principal(principal: Principal) {
+    compliance::freeze_principal(principal);
+}
+
+#[ic_cdk::update]
+fn unfreeze_principal(principal: Principal) {
+    compliance::unfreeze_principal(principal);
+}
+
+#[ic_cdk::update]
+fn block_address(address: H160t) {
+    compliance::block_address(address.into());
+}
+
+#[ic_cdk::update]
+fn unblock_address(address: H160t) {
+    compliance::unblock_address(address.into());
+}
+
+#[ic_cdk::query]
+fn get_frozen_principals() -> Vec<Principal> {
+    compliance::get_frozen_principals()
+}
+
+#[ic_cdk::query]
+fn get_blocked_addresses() -> Vec<H160t> {
+    compliance::get_blocked_addresses()
+}
+
+#[ic_cdk::query]
+fn get_quarantined_deposits() -> Vec<QuarantinedDeposit> {
+    compliance::get_quarantined_deposits()
+}
+
+/*
+ * Tokens
+ */
+
+#[ic_cdk::update]
+async fn add_token(chain_id: u32, token: H160t) -> Result<TokenMetadata, HarmonizeError> {
+    tokens::add_token(chain_id, token.into()).await
+}
+
+#[ic_cdk::update]
+fn remove_token(chain_id: u32, token: H160t) -> Result<(), HarmonizeError> {
+    tokens::remove_token(chain_id, token.into())
+}
+
+#[ic_cdk::query]
+fn get_tokens(chain_id: u32) -> Vec<TokenInfo> {
+    tokens::get_tokens(chain_id)
+}
+
+#[ic_cdk::query]
+fn get_held_deposits(chain_id: u32) -> Vec<HeldDeposit> {
+    tokens::get_held_deposits(chain_id)
+}
+
+#[ic_cdk::query]
+fn get_pending_deposits(wallet: Principal, subaccount: Option<Subaccount>) -> Vec<PendingDeposit> {
+    state::get_pending_deposits(Account::new(wallet, subaccount))
+}
+
+#[ic_cdk::update]
+fn create_canonical_asset(name: String, symbol: String, decimals: u8) -> u32 {
+    canonical::create_canonical_asset(name, symbol, decimals)
+}
+
+#[ic_cdk::update]
+fn map_canonical_token(id: u32, chain_id: u32, token: H160t) -> Result<(), HarmonizeError> {
+    canonical::map_canonical_token(id, chain_id, token.into())
+}
+
+#[ic_cdk::query]
+fn get_canonical_assets() -> Vec<CanonicalAsset> {
+    canonical::get_canonical_assets()
+}
+
 /*
  * SIWE
  */
@@ -82,33 +327,206 @@ async fn get_siwe_session_address() -> Result<H160t, HarmonizeError> {
  */
 
 #[ic_cdk::query]
-fn get_erc20_balance(wallet: Principal, network_id: u32, token: H160t) -> U256t {
-    wallet::get_erc20_balance(wallet, network_id, token.into()).into()
+fn get_erc20_balance(wallet: Principal, network_id: u32, token: H160t, subaccount: Option<Subaccount>) -> U256t {
+    wallet::get_erc20_balance(Account::new(wallet, subaccount), network_id, token.into()).into()
+}
+
+#[ic_cdk::query]
+fn get_eth_balance(wallet: Principal, network_id: u32, subaccount: Option<Subaccount>) -> U256t {
+    wallet::get_eth_balance(Account::new(wallet, subaccount), network_id).into()
 }
 
 #[ic_cdk::query]
-fn get_eth_balance(wallet: Principal, network_id: u32) -> U256t {
-    wallet::get_eth_balance(wallet, network_id).into()
+fn get_balance(wallet: Principal, asset: AssetId, subaccount: Option<Subaccount>) -> Balance {
+    wallet::get_balance(Account::new(wallet, subaccount), asset)
+}
+
+#[ic_cdk::query]
+fn get_balances(wallet: Principal, subaccount: Option<Subaccount>) -> Vec<ChainBalances> {
+    wallet::get_balances(Account::new(wallet, subaccount))
+}
+
+#[ic_cdk::query]
+fn get_transactions(wallet: Principal, start: u64, limit: u64, subaccount: Option<Subaccount>) -> TransactionPage {
+    history::get_transactions(Account::new(wallet, subaccount), start, limit)
+}
+
+#[ic_cdk::query]
+fn get_pending_transactions(wallet: Principal, subaccount: Option<Subaccount>) -> Vec<PendingTransaction> {
+    settlement::get_pending_transactions(Account::new(wallet, subaccount))
+}
+
+#[ic_cdk::update]
+async fn settle_transaction(tx_hash: String) -> Result<Settlement, HarmonizeError> {
+    settlement::settle_transaction(tx_hash).await
+}
+
+#[ic_cdk::update]
+fn transfer_erc20(
+    to: Principal,
+    network_id: u32,
+    token: H160t,
+    amount: U256t,
+    from_subaccount: Option<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+) -> Result<u64, HarmonizeError> {
+    let caller = ic_cdk::caller();
+    let from = Account::new(caller, from_subaccount);
+    let args = (to, network_id, token, amount, from_subaccount, to_subaccount);
+    dedup::deduplicate(caller, memo, created_at_time, &args, || {
+        let id = wallet::transfer_erc20(from, Account::new(to, to_subaccount), network_id, token.into(), amount.into())?;
+        Ok(Outcome::Transaction { id })
+    })?
+    .into_transaction_id()
+    .map_err(Into::into)
 }
 
 #[ic_cdk::update]
-fn transfer_erc20(from: Principal, to: Principal, network_id: u32, token: H160t, amount: U256t) -> Result<(), HarmonizeError> {
-    wallet::transfer_erc20(from, to, network_id, token.into(), amount.into())
+fn transfer_eth(
+    to: Principal,
+    network_id: u32,
+    amount: U256t,
+    from_subaccount: Option<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+) -> Result<u64, HarmonizeError> {
+    let caller = ic_cdk::caller();
+    let from = Account::new(caller, from_subaccount);
+    let args = (to, network_id, amount, from_subaccount, to_subaccount);
+    dedup::deduplicate(caller, memo, created_at_time, &args, || {
+        let id = wallet::transfer_eth(from, Account::new(to, to_subaccount), network_id, amount.into())?;
+        Ok(Outcome::Transaction { id })
+    })?
+    .into_transaction_id()
+    .map_err(Into::into)
 }
 
 #[ic_cdk::update]
-fn transfer_eth(from: Principal, to: Principal, network_id: u32, amount: U256t) -> Result<(), HarmonizeError> {
-    wallet::transfer_eth(from, to, network_id, amount.into())
+fn batch_transfer(legs: Vec<TransferLeg>) -> Result<u64, HarmonizeError> {
+    wallet::batch_transfer(ic_cdk::caller(), legs)
 }
 
 #[ic_cdk::update]
-async fn withdraw_erc20(to: H160t, network_id: u32, token: H160t, amount: U256t) -> Result<(), HarmonizeError> {
-    wallet::withdraw_erc20(ic_cdk::caller(), to.into(), network_id, token.into(), amount.into()).await
+fn approve(
+    spender: Account,
+    asset: AssetId,
+    amount: U256t,
+    expires_at: Option<u64>,
+    from_subaccount: Option<Subaccount>,
+) -> Result<(), HarmonizeError> {
+    let owner = Account::new(ic_cdk::caller(), from_subaccount);
+    wallet::approve(owner, spender.normalized(), asset, amount.into(), expires_at)
+}
+
+#[ic_cdk::query]
+fn allowance(owner: Account, spender: Account, asset: AssetId) -> Allowance {
+    wallet::allowance(owner.normalized(), spender.normalized(), asset)
+}
+
+#[ic_cdk::update]
+fn transfer_from(
+    from: Account,
+    to: Account,
+    asset: AssetId,
+    amount: U256t,
+    spender_subaccount: Option<Subaccount>,
+) -> Result<(), HarmonizeError> {
+    let spender = Account::new(ic_cdk::caller(), spender_subaccount);
+    wallet::transfer_from(spender, from.normalized(), to.normalized(), asset, amount.into())
+}
+
+#[ic_cdk::update]
+async fn withdraw_erc20(
+    to: H160t,
+    network_id: u32,
+    token: H160t,
+    amount: U256t,
+    from_subaccount: Option<Subaccount>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+) -> Result<String, HarmonizeError> {
+    let caller = ic_cdk::caller();
+    let from = Account::new(caller, from_subaccount);
+    let args = (to, network_id, token, amount, from_subaccount);
+    dedup::deduplicate_async(caller, memo, created_at_time, &args, async {
+        let tx_hash = wallet::withdraw_erc20(from, to.into(), network_id, token.into(), amount.into()).await?;
+        Ok(Outcome::Withdrawal { tx_hash })
+    })
+    .await?
+    .into_tx_hash()
+}
+
+#[ic_cdk::update]
+async fn withdraw_eth(
+    to: H160t,
+    network_id: u32,
+    amount: U256t,
+    from_subaccount: Option<Subaccount>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+) -> Result<String, HarmonizeError> {
+    let caller = ic_cdk::caller();
+    let from = Account::new(caller, from_subaccount);
+    let args = (to, network_id, amount, from_subaccount);
+    dedup::deduplicate_async(caller, memo, created_at_time, &args, async {
+        let tx_hash = wallet::withdraw_eth(from, to.into(), network_id, amount.into()).await?;
+        Ok(Outcome::Withdrawal { tx_hash })
+    })
+    .await?
+    .into_tx_hash()
+}
+
+#[ic_cdk::update]
+async fn withdraw_canonical(
+    id: u32,
+    to: H160t,
+    network_id: u32,
+    amount: U256t,
+    from_subaccount: Option<Subaccount>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+) -> Result<String, HarmonizeError> {
+    let caller = ic_cdk::caller();
+    let from = Account::new(caller, from_subaccount);
+    let args = (id, to, network_id, amount, from_subaccount);
+    dedup::deduplicate_async(caller, memo, created_at_time, &args, async {
+        let tx_hash = canonical::withdraw_canonical(from, to.into(), id, network_id, amount.into()).await?;
+        Ok(Outcome::Withdrawal { tx_hash })
+    })
+    .await?
+    .into_tx_hash()
+}
+
+/*
+ * ICRC-1
+ */
+
+#[ic_cdk::query]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    icrc1::supported_standards()
+}
+
+#[ic_cdk::query]
+fn icrc1_metadata(ledger: AssetId) -> Vec<(String, MetadataValue)> {
+    icrc1::metadata(ledger)
+}
+
+#[ic_cdk::query]
+fn icrc1_total_supply(ledger: AssetId) -> Nat {
+    icrc1::total_supply(ledger)
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(ledger: AssetId, account: Account) -> Nat {
+    icrc1::balance_of(ledger, account)
 }
 
 #[ic_cdk::update]
-async fn withdraw_eth(to: H160t, network_id: u32, amount: U256t) -> Result<(), HarmonizeError> {
-    wallet::withdraw_eth(ic_cdk::caller(), to.into(), network_id, amount.into()).await
+fn icrc1_transfer(ledger: AssetId, arg: TransferArg) -> Result<Nat, TransferError> {
+    icrc1::transfer(ic_cdk::caller(), ledger, arg)
 }
 
 // Enable Candid export
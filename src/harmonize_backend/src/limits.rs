@@ -0,0 +1,218 @@
+//! Rolling-window limits on withdrawals.
+//!
+//! Limits apply to the asset that leaves the canister's address on a network,
+//! either to each user on their own or to all users together. A withdrawal
+//! counts against the limits from the moment it is requested, and stops
+//! counting if it fails or once it falls out of the window.
+
+use std::collections::{BTreeMap, VecDeque};
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::types::U256;
+use thiserror::Error;
+
+use crate::{
+    access_control::{require_role, Role},
+    audit,
+    state::{mutate_state, read_state},
+    types::U256t,
+    wallet::AssetId,
+    HarmonizeError,
+};
+
+#[derive(Error, Debug, CandidType)]
+pub enum RateLimitError {
+    #[error("The withdrawal limit of the user is exceeded, {remaining} remaining")]
+    UserLimitExceeded { remaining: U256t },
+    #[error("The global withdrawal limit is exceeded, {remaining} remaining")]
+    GlobalLimitExceeded { remaining: U256t },
+    #[error("The window of a limit cannot be empty")]
+    EmptyWindow,
+}
+
+/// Who a limit applies to.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LimitScope {
+    /// Each user, across all of their subaccounts.
+    User,
+    /// All users together.
+    Global,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy)]
+pub struct WithdrawalLimit {
+    /// The maximum amount withdrawn within any window.
+    pub amount: U256t,
+    /// The length of the window, in nanoseconds.
+    pub window_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LimitUsage {
+    pub asset: AssetId,
+    pub scope: LimitScope,
+    pub limit: WithdrawalLimit,
+    /// The amount withdrawn within the current window.
+    pub used: U256t,
+}
+
+/// A withdrawal that still counts against the limits.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecentWithdrawal {
+    pub timestamp: u64,
+    pub user: Principal,
+    pub asset: AssetId,
+    pub amount: U256t,
+}
+
+#[derive(Default)]
+pub struct RateLimits {
+    pub limits: BTreeMap<(AssetId, LimitScope), WithdrawalLimit>,
+    /// Withdrawals of limited assets, oldest first.
+    pub withdrawals: VecDeque<RecentWithdrawal>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the limit of `asset` for `scope`, or removes it if `limit` is `None`.
+    pub fn set(&mut self, asset: AssetId, scope: LimitScope, limit: Option<WithdrawalLimit>) -> Result<(), RateLimitError> {
+        match limit {
+            Some(limit) if limit.window_nanos == 0 => return Err(RateLimitError::EmptyWindow),
+            Some(limit) => self.limits.insert((asset, scope), limit),
+            None => self.limits.remove(&(asset, scope)),
+        };
+        Ok(())
+    }
+
+    /// The amount of `asset` withdrawn within the window ending at `now`, by `user` or by everyone.
+    pub fn used(&self, asset: &AssetId, user: Option<Principal>, window_nanos: u64, now: u64) -> U256 {
+        self.withdrawals
+            .iter()
+            .filter(|w| now.saturating_sub(w.timestamp) < window_nanos)
+            .filter(|w| w.asset == *asset && (user.is_none() || user == Some(w.user)))
+            .fold(U256::zero(), |used, w| used.saturating_add(w.amount.0))
+    }
+
+    /// Forgets the withdrawals that no limit looks back to anymore.
+    pub fn prune(&mut self, now: u64) {
+        let limits = &self.limits;
+        self.withdrawals.retain(|w| {
+            [LimitScope::User, LimitScope::Global]
+                .iter()
+                .filter_map(|scope| limits.get(&(w.asset, *scope)))
+                .any(|limit| now.saturating_sub(w.timestamp) < limit.window_nanos)
+        });
+    }
+
+    /// Counts a withdrawal against the limits of its asset, or rejects it if it would exceed any of them.
+    pub fn reserve(&mut self, user: Principal, asset: AssetId, amount: U256, now: u64) -> Result<Option<RecentWithdrawal>, RateLimitError> {
+        self.prune(now);
+        let mut limited = false;
+        for scope in [LimitScope::User, LimitScope::Global] {
+            let Some(limit) = self.limits.get(&(asset, scope)) else {
+                continue;
+            };
+            limited = true;
+            let user = (scope == LimitScope::User).then_some(user);
+            let used = self.used(&asset, user, limit.window_nanos, now);
+            let remaining = limit.amount.0.saturating_sub(used);
+            if amount > remaining {
+                return Err(match scope {
+                    LimitScope::User => RateLimitError::UserLimitExceeded { remaining: remaining.into() },
+                    LimitScope::Global => RateLimitError::GlobalLimitExceeded { remaining: remaining.into() },
+                });
+            }
+        }
+        if !limited {
+            return Ok(None);
+        }
+        let withdrawal = RecentWithdrawal { timestamp: now, user, asset, amount: amount.into() };
+        self.withdrawals.push_back(withdrawal.clone());
+        Ok(Some(withdrawal))
+    }
+
+    /// Stops counting a withdrawal that failed.
+    pub fn release(&mut self, withdrawal: &RecentWithdrawal) {
+        if let Some(index) = self.withdrawals.iter().position(|w| w == withdrawal) {
+            self.withdrawals.remove(index);
+        }
+    }
+
+    pub fn usage(&self, user: Principal, now: u64) -> Vec<LimitUsage> {
+        self.limits
+            .iter()
+            .map(|((asset, scope), limit)| {
+                let user = (*scope == LimitScope::User).then_some(user);
+                LimitUsage {
+                    asset: *asset,
+                    scope: *scope,
+                    limit: *limit,
+                    used: self.used(asset, user, limit.window_nanos, now).into(),
+                }
+            })
+            .collect()
+    }
+}
+
+// Public API
+
+/// Sets the withdrawal limit of `asset` for `scope`, or removes it if `limit` is `None`.
+pub fn set_withdrawal_limit(asset: AssetId, scope: LimitScope, limit: Option<WithdrawalLimit>) -> Result<(), HarmonizeError> {
+    require_role(Role::Operator);
+    let old_limit = mutate_state(|s| -> Result<_, RateLimitError> {
+        let old_limit = s.rate_limits.limits.get(&(asset, scope)).copied();
+        s.rate_limits.set(asset, scope, limit)?;
+        Ok(old_limit)
+    })?;
+    audit::record_change("set_withdrawal_limit", (asset, scope, limit), old_limit, limit);
+    Ok(())
+}
+
+/// Returns every configured limit with the amount `user`, or everyone for global limits, withdrew within its window.
+pub fn get_withdrawal_limits(user: Principal) -> Vec<LimitUsage> {
+    let now = ic_cdk::api::time();
+    read_state(|s| s.rate_limits.usage(user, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_roll_over_their_window() {
+        let mut limits = RateLimits::new();
+        let asset = AssetId::Native { chain_id: 1 };
+        let (alice, bob) = (Principal::anonymous(), Principal::management_canister());
+        let limit = |amount: u64, window_nanos| Some(WithdrawalLimit { amount: U256::from(amount).into(), window_nanos });
+        limits.set(asset, LimitScope::User, limit(10, 100)).unwrap();
+        limits.set(asset, LimitScope::Global, limit(15, 1_000)).unwrap();
+
+        // Unlimited assets are not tracked
+        assert!(limits.reserve(alice, AssetId::Native { chain_id: 2 }, U256::from(100), 0).unwrap().is_none());
+
+        limits.reserve(alice, asset, U256::from(8), 0).unwrap();
+        assert!(matches!(
+            limits.reserve(alice, asset, U256::from(3), 50),
+            Err(RateLimitError::UserLimitExceeded { remaining }) if remaining.0 == U256::from(2)
+        ));
+        limits.reserve(bob, asset, U256::from(5), 50).unwrap();
+        assert!(matches!(
+            limits.reserve(bob, asset, U256::from(3), 60),
+            Err(RateLimitError::GlobalLimitExceeded { .. })
+        ));
+
+        // A failed withdrawal no longer counts
+        let failed = limits.reserve(bob, asset, U256::from(2), 60).unwrap().unwrap();
+        limits.release(&failed);
+        assert_eq!(limits.used(&asset, None, 1_000, 60), U256::from(13));
+
+        // Alice's own window has rolled over, but the global one has not
+        assert!(matches!(
+            limits.reserve(alice, asset, U256::from(3), 150),
+            Err(RateLimitError::GlobalLimitExceeded { .. })
+        ));
+        limits.reserve(alice, asset, U256::from(2), 150).unwrap();
+    }
+}
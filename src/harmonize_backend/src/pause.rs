@@ -0,0 +1,108 @@
+//! Emergency switches that halt an operation on one network or on all of them.
+
+use std::collections::BTreeSet;
+use candid::{CandidType, Deserialize};
+use thiserror::Error;
+
+use crate::access_control::{require_role, Role};
+use crate::audit;
+use crate::state::{mutate_state, read_state};
+
+#[derive(Error, Debug, CandidType)]
+pub enum PauseError {
+    #[error("{operation:?} is paused")]
+    Paused { operation: PausableOperation, chain_id: Option<u32> },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PausableOperation {
+    /// Fetching the logs of the endpoint contracts.
+    LogScraping,
+    /// Crediting deposits found in the logs, or held until their token was listed.
+    DepositCrediting,
+    Transfers,
+    Withdrawals,
+}
+
+/// A pause of an operation on one network, or on all of them if `chain_id` is `None`.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PauseFlag {
+    pub operation: PausableOperation,
+    pub chain_id: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct Pauses {
+    pub flags: BTreeSet<PauseFlag>,
+}
+
+impl Pauses {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set(&mut self, flag: PauseFlag, paused: bool) {
+        if paused {
+            self.flags.insert(flag);
+        } else {
+            self.flags.remove(&flag);
+        }
+    }
+
+    /// Checks that `operation` is paused neither globally nor on `chain_id`.
+    pub fn check(&self, operation: PausableOperation, chain_id: Option<u32>) -> Result<(), PauseError> {
+        for chain_id in [None, chain_id] {
+            if self.flags.contains(&PauseFlag { operation, chain_id }) {
+                return Err(PauseError::Paused { operation, chain_id });
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn check(operation: PausableOperation, chain_id: Option<u32>) -> Result<(), PauseError> {
+    read_state(|s| s.pauses.check(operation, chain_id))
+}
+
+// Public API
+
+/// Pauses or resumes `operation` on `chain_id`, or globally if it is `None`.
+pub fn set_paused(operation: PausableOperation, chain_id: Option<u32>, paused: bool) {
+    require_role(Role::Pauser);
+    let flag = PauseFlag { operation, chain_id };
+    let was_paused = mutate_state(|s| {
+        let was_paused = s.pauses.flags.contains(&flag);
+        s.pauses.set(flag, paused);
+        was_paused
+    });
+    audit::record_change("set_paused", (operation, chain_id, paused), was_paused, paused);
+}
+
+pub fn get_paused() -> Vec<PauseFlag> {
+    read_state(|s| s.pauses.flags.iter().copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_pauses_cover_every_network() {
+        let mut pauses = Pauses::new();
+        let flag = |chain_id| PauseFlag { operation: PausableOperation::Withdrawals, chain_id };
+
+        pauses.set(flag(Some(1)), true);
+        assert!(pauses.check(PausableOperation::Withdrawals, Some(1)).is_err());
+        assert!(pauses.check(PausableOperation::Withdrawals, Some(2)).is_ok());
+        assert!(pauses.check(PausableOperation::Transfers, Some(1)).is_ok());
+
+        pauses.set(flag(None), true);
+        assert!(matches!(
+            pauses.check(PausableOperation::Withdrawals, Some(2)),
+            Err(PauseError::Paused { chain_id: None, .. })
+        ));
+        pauses.set(flag(None), false);
+        pauses.set(flag(Some(1)), false);
+        assert!(pauses.check(PausableOperation::Withdrawals, Some(1)).is_ok());
+    }
+}
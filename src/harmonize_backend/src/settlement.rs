@@ -0,0 +1,226 @@
+//! Settlement of transactions that were broadcast without a receipt.
+//!
+//! The gas and withdrawal holds of such a transaction stay in place until its
+//! receipt shows up, when the actual gas cost is captured and the withdrawal
+//! completed or refunded, or until it is known to be dropped, when every hold
+//! is given back.
+
+use std::collections::BTreeMap;
+use candid::{CandidType, Deserialize, Nat};
+use ethers_core::types::U256;
+use ic_cdk::println;
+
+use crate::{
+    access_control::{require_role, Role},
+    audit,
+    chain_fusion::{evm_rpc::TransactionReceipt, job::safe::{self, TransactionError}},
+    limits::RecentWithdrawal,
+    state::{mutate_state, read_state},
+    types::{H160t, U256t},
+    wallet::{self, Account, AssetId},
+    HarmonizeError,
+};
+
+/// The maximum gas cost held from a wallet for a transaction.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct GasHold {
+    pub payer: Account,
+    pub max_gas_cost: U256t,
+}
+
+/// The amount and fee of a withdrawal held from a wallet.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct WithdrawalHold {
+    pub from: Account,
+    pub to: H160t,
+    pub asset: AssetId,
+    /// The asset that leaves the canister's address, see [`wallet::withdraw_with`].
+    pub outflow: AssetId,
+    pub amount: U256t,
+    pub fee: U256t,
+    /// The usage of the withdrawal limits, given back if the withdrawal does not happen.
+    pub limited: Option<RecentWithdrawal>,
+}
+
+/// A transaction that was broadcast but has no receipt yet.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PendingTransaction {
+    pub tx_hash: String,
+    pub chain_id: u32,
+    pub nonce: U256t,
+    /// Nanoseconds since the UNIX epoch.
+    pub sent_at: u64,
+    pub gas: Option<GasHold>,
+    pub withdrawal: Option<WithdrawalHold>,
+}
+
+/// How a pending transaction was settled.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum Settlement {
+    /// There is no receipt yet and the holds are kept.
+    Pending,
+    /// The transaction was mined, its gas was paid and its withdrawal completed.
+    Mined,
+    /// The transaction was mined and reverted, its gas was paid and its withdrawal refunded.
+    Reverted,
+    /// The transaction can no longer be mined and all of its holds were refunded.
+    Dropped,
+}
+
+#[derive(Default)]
+pub struct Settlements {
+    /// The pending transactions by hash.
+    pub pending: BTreeMap<String, PendingTransaction>,
+}
+
+impl Settlements {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts tracking a broadcast transaction until its holds are settled.
+    pub fn track(&mut self, tx_hash: String, chain_id: u32, nonce: U256, now: u64) {
+        self.pending.entry(tx_hash.clone()).or_insert(PendingTransaction {
+            tx_hash,
+            chain_id,
+            nonce: nonce.into(),
+            sent_at: now,
+            gas: None,
+            withdrawal: None,
+        });
+    }
+
+    pub fn hold_gas(&mut self, tx_hash: &str, hold: GasHold) {
+        self.get_mut(tx_hash).gas = Some(hold);
+    }
+
+    pub fn hold_withdrawal(&mut self, tx_hash: &str, hold: WithdrawalHold) {
+        self.get_mut(tx_hash).withdrawal = Some(hold);
+    }
+
+    fn get_mut(&mut self, tx_hash: &str) -> &mut PendingTransaction {
+        self.pending
+            .get_mut(tx_hash)
+            .unwrap_or_else(|| panic!("BUG: transaction {tx_hash} is not tracked"))
+    }
+}
+
+/// Settles the holds of a pending transaction if its outcome is known.
+///
+/// A transaction without a receipt is only considered dropped once the
+/// canister's address has a higher mined nonce, since it can still be mined
+/// before that.
+pub async fn settle(tx_hash: &str) -> Result<Settlement, TransactionError> {
+    let pending = read_state(|s| s.settlements.pending.get(tx_hash).cloned())
+        .ok_or_else(|| TransactionError::NotPending { tx_hash: tx_hash.to_string() })?;
+    let mut receipt = find_receipt(pending.chain_id, tx_hash).await?;
+    if receipt.is_none() {
+        let mined_count = safe::get_mined_transaction_count(pending.chain_id).await?;
+        if mined_count <= pending.nonce.0 {
+            return Ok(Settlement::Pending);
+        }
+        // The nonce is used, look again in case the transaction was mined since the first lookup
+        receipt = find_receipt(pending.chain_id, tx_hash).await?;
+    }
+
+    mutate_state(|s| {
+        // Another settlement of the same transaction may have finished in the meantime
+        let Some(pending) = s.settlements.pending.remove(tx_hash) else {
+            return Err(TransactionError::NotPending { tx_hash: tx_hash.to_string() });
+        };
+        let (gas_cost, settlement) = match &receipt {
+            Some(receipt) => {
+                let gas_cost = safe::biguint_to_u256(receipt.effectiveGasPrice.0.clone())
+                    * safe::biguint_to_u256(receipt.gasUsed.0.clone());
+                let reverted = receipt.status == Nat::from(0u8);
+                (gas_cost, if reverted { Settlement::Reverted } else { Settlement::Mined })
+            }
+            None => (U256::zero(), Settlement::Dropped),
+        };
+        if let Some(hold) = &pending.gas {
+            let gas_cost = gas_cost.min(hold.max_gas_cost.0);
+            if let Err(e) = safe::charge_gas(s, hold.payer, pending.chain_id, hold.max_gas_cost.0, gas_cost) {
+                println!("Error settling the gas of {}: {:?}", pending.tx_hash, e);
+            }
+        }
+        if let Some(hold) = &pending.withdrawal {
+            let result = match settlement {
+                Settlement::Mined => wallet::complete_withdrawal(s, hold, pending.tx_hash.clone()),
+                _ => {
+                    // The liquidity was taken out when the withdrawal started
+                    if let AssetId::Erc20 { chain_id, token } = hold.outflow {
+                        s.canonical_assets.restore_token_liquidity(chain_id, token.0, hold.amount.0);
+                    }
+                    wallet::refund_withdrawal(s, hold)
+                }
+            };
+            if let Err(e) = result {
+                println!("Error settling withdrawal {}: {:?}", pending.tx_hash, e);
+            }
+        }
+        Ok(settlement)
+    })
+}
+
+async fn find_receipt(chain_id: u32, tx_hash: &str) -> Result<Option<TransactionReceipt>, TransactionError> {
+    match safe::get_receipt(chain_id, tx_hash.to_string()).await {
+        Ok(receipt) => Ok(Some(receipt)),
+        Err(TransactionError::NoReceipt) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tries to settle every pending transaction, leaving the ones whose outcome is unknown.
+pub async fn settle_pending_transactions() {
+    let tx_hashes = read_state(|s| s.settlements.pending.keys().cloned().collect::<Vec<_>>());
+    for tx_hash in tx_hashes {
+        match settle(&tx_hash).await {
+            Ok(Settlement::Pending) => {}
+            Ok(settlement) => println!("Settled transaction {}: {:?}", tx_hash, settlement),
+            Err(e) => println!("Error settling transaction {}: {:?}", tx_hash, e),
+        }
+    }
+}
+
+// Public API
+
+pub async fn settle_transaction(tx_hash: String) -> Result<Settlement, HarmonizeError> {
+    require_role(Role::Operator);
+    let settlement = settle(&tx_hash).await?;
+    audit::record("settle_transaction", (tx_hash, settlement.clone()));
+    Ok(settlement)
+}
+
+pub fn get_pending_transactions(wallet: Account) -> Vec<PendingTransaction> {
+    read_state(|s| {
+        s.settlements
+            .pending
+            .values()
+            .filter(|pending| {
+                pending.gas.as_ref().is_some_and(|hold| hold.payer == wallet)
+                    || pending.withdrawal.as_ref().is_some_and(|hold| hold.from == wallet)
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holds_attach_to_the_tracked_transaction() {
+        let mut settlements = Settlements::new();
+        let payer = Account::from(candid::Principal::anonymous());
+        settlements.track("0x1".to_string(), 1, U256::from(3), 10);
+        settlements.hold_gas("0x1", GasHold { payer, max_gas_cost: U256::from(100).into() });
+        // Tracking the same transaction again keeps its holds
+        settlements.track("0x1".to_string(), 1, U256::from(3), 20);
+
+        let pending = settlements.pending.get("0x1").unwrap();
+        assert_eq!(pending.sent_at, 10);
+        assert_eq!(pending.gas.as_ref().unwrap().max_gas_cost.0, U256::from(100));
+        assert!(pending.withdrawal.is_none());
+    }
+}
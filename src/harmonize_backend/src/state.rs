@@ -1,15 +1,33 @@
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ethers_core::types::{H160, U256};
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
 
 use crate::chain_fusion::evm_rpc::{LogEntry, BlockTag, RpcService, RpcServices};
+use crate::chain_fusion::eth_get_logs::DEFAULT_MAX_BLOCK_SPREAD;
 use crate::chain_fusion::ecdsa;
-use crate::chain_fusion::job::events::{DepositEthEvent, DepositErc20Event};
-use crate::chain_fusion::{LogSource, TaskType};
-use crate::types::H160t;
-use crate::wallet::Wallets;
+use crate::chain_fusion::job::events::Event;
+use crate::chain_fusion::{self, LogSource, TaskType, DEFAULT_MAX_CONCURRENT_SCRAPES};
+use crate::access_control::{require_role, Role, Roles};
+use crate::audit;
+use crate::canonical::CanonicalAssets;
+use crate::compliance::Compliance;
+use crate::dedup::Deduplication;
+use crate::fees::{Fee, FeeError, FeeSchedule, Operation};
+use crate::history::{History, TransactionKind};
+use crate::limits::RateLimits;
+use crate::pause::Pauses;
+use crate::settlement::Settlements;
+use crate::storage::{get_memory, Memory, PROCESSED_LOGS};
+use crate::timelock::Timelock;
+use crate::tokens::Tokens;
+use crate::types::{H160t, U256t};
+use crate::wallet::{Account, AssetId, Wallets};
+use crate::HarmonizeError;
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct NetworkInit {
@@ -18,6 +36,13 @@ pub struct NetworkInit {
     pub last_scraped_block_number: Nat,
     pub get_logs_address: Vec<H160t>,
     pub block_tag: BlockTag,
+    /// How many blocks must follow the block of a deposit before it is credited.
+    /// Defaults to zero, which leaves reorg protection to the block tag.
+    pub confirmations: Option<u64>,
+    /// Defaults to [`DEFAULT_MAX_BLOCK_SPREAD`].
+    pub max_block_spread: Option<u64>,
+    /// Defaults to the topics of the registered events.
+    pub get_logs_topics: Option<Vec<Vec<String>>>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -28,6 +53,9 @@ pub struct NetworkMut {
     pub block_tag: Option<BlockTag>,
     pub get_logs_address: Option<Vec<H160t>>,
     pub nonce: Option<u128>,
+    pub confirmations: Option<u64>,
+    pub max_block_spread: Option<u64>,
+    pub get_logs_topics: Option<Vec<Vec<String>>>,
 }
 
 impl Default for NetworkMut {
@@ -45,6 +73,9 @@ impl NetworkMut {
             get_logs_address: None,
             block_tag: None,
             nonce: None,
+            confirmations: None,
+            max_block_spread: None,
+            get_logs_topics: None,
         }
     }
     pub fn into_init(self) -> Option<NetworkInit> {
@@ -54,6 +85,9 @@ impl NetworkMut {
             last_scraped_block_number: self.last_scraped_block_number?,
             get_logs_address: self.get_logs_address?,
             block_tag: self.block_tag?,
+            confirmations: self.confirmations,
+            max_block_spread: self.max_block_spread,
+            get_logs_topics: self.get_logs_topics,
         })
     }
 }
@@ -64,16 +98,45 @@ pub struct Network {
     pub rpc_service: RpcService,
     pub last_scraped_block_number: Nat,
     pub last_observed_block_number: Option<Nat>,
+    /// The last observed head of the chain, which may be past the block tag.
+    pub latest_block_number: Option<Nat>,
     pub last_processed_block_number: Option<Nat>,
     pub logs_to_process: BTreeMap<LogSource, LogEntry>,
     pub get_logs_address: Vec<H160>,
-    pub processed_logs: BTreeMap<LogSource, LogEntry>,
+    /// The topic filters of `eth_getLogs`, by position. Every log matches if empty.
+    pub get_logs_topics: Vec<Vec<String>>,
+    pub processed_logs: ProcessedLogs,
     pub skipped_blocks: BTreeSet<Nat>,
     pub block_tag: BlockTag,
     pub nonce: U256,
+    /// How many blocks must follow the block of a deposit before it is credited.
+    pub confirmations: u64,
+    /// Logs whose block does not have enough confirmations yet.
+    pub pending_logs: BTreeMap<LogSource, LogEntry>,
+    /// The hashes of the recently scraped blocks that may still be reorged out.
+    pub block_hashes: BTreeMap<Nat, String>,
+    /// The largest number of blocks after the first one that a single `eth_getLogs` call queries.
+    pub max_block_spread: u64,
+    /// The current spread, which shrinks when providers reject a range and grows back after successful calls.
+    pub block_spread: u64,
 }
 
 impl Network {
+    /// The configurable fields of the network, in the shape they are set with.
+    pub fn config(&self) -> NetworkMut {
+        NetworkMut {
+            rpc_services: Some(self.rpc_services.clone()),
+            rpc_service: Some(self.rpc_service.clone()),
+            last_scraped_block_number: Some(self.last_scraped_block_number.clone()),
+            block_tag: Some(self.block_tag.clone()),
+            get_logs_address: Some(self.get_logs_address.iter().map(|address| (*address).into()).collect()),
+            nonce: Some(self.nonce.as_u128()),
+            confirmations: Some(self.confirmations),
+            max_block_spread: Some(self.max_block_spread),
+            get_logs_topics: Some(self.get_logs_topics.clone()),
+        }
+    }
+
     pub fn mutate_with(&mut self, init: NetworkMut) {
         if let Some(rpc_services) = init.rpc_services {
             self.rpc_services = rpc_services;
@@ -90,6 +153,116 @@ impl Network {
         if let Some(nonce) = init.nonce {
             self.nonce = U256::from(nonce);
         }
+        if let Some(confirmations) = init.confirmations {
+            self.confirmations = confirmations;
+        }
+        if let Some(max_block_spread) = init.max_block_spread {
+            self.max_block_spread = max_block_spread;
+            self.block_spread = max_block_spread;
+        }
+        if let Some(get_logs_topics) = init.get_logs_topics {
+            self.get_logs_topics = get_logs_topics;
+        }
+    }
+
+    /// Doubles the block spread after a successful `eth_getLogs` call, up to the maximum.
+    pub fn grow_block_spread(&mut self) {
+        self.block_spread = self.block_spread.saturating_mul(2).max(1).min(self.max_block_spread);
+    }
+
+    /// Whether blocks that may still be reorged out are scraped.
+    pub fn scrapes_unconfirmed_blocks(&self) -> bool {
+        self.confirmations > 0 || !matches!(self.block_tag, BlockTag::Latest)
+    }
+
+    /// The highest block that has enough confirmations when `head` is the head of the
+    /// chain, and that the block tag has reached.
+    pub fn confirmed_block_number(&self, head: &Nat) -> Nat {
+        let confirmations = Nat::from(self.confirmations);
+        let confirmed = if *head < confirmations {
+            Nat::from(0u32)
+        } else {
+            head.clone() - confirmations
+        };
+        match &self.last_observed_block_number {
+            Some(tagged) if *tagged < confirmed => tagged.clone(),
+            _ => confirmed,
+        }
+    }
+
+    /// Records a scraped log, which waits for its confirmations before being processed.
+    pub fn record_pending_log(&mut self, log_entry: &LogEntry) {
+        let event_source = log_entry.source();
+        if self.logs_to_process.contains_key(&event_source) || self.processed_logs.contains_key(&event_source) {
+            return;
+        }
+        if let (Some(number), Some(hash)) = (&log_entry.blockNumber, &log_entry.blockHash) {
+            self.block_hashes.insert(number.clone(), hash.clone());
+        }
+        self.pending_logs.insert(event_source, log_entry.clone());
+    }
+
+    /// Moves the pending logs that have enough confirmations to the logs to process, and
+    /// forgets the hashes of the blocks that are considered final.
+    pub fn confirm_pending_logs(&mut self, head: &Nat) {
+        let confirmed = self.confirmed_block_number(head);
+        let is_confirmed = |entry: &LogEntry| entry.blockNumber.as_ref().is_some_and(|number| *number <= confirmed);
+        let (ready, pending) = std::mem::take(&mut self.pending_logs)
+            .into_iter()
+            .partition(|(_, entry)| is_confirmed(entry));
+        self.pending_logs = pending;
+        for (_, log_entry) in ready.iter() {
+            self.record_log_to_process(log_entry);
+        }
+        self.block_hashes.retain(|number, _| *number >= confirmed);
+    }
+
+    /// Drops what was scraped after `ancestor`, the last block shared with the canonical chain,
+    /// so that it is scraped again. Returns the sources of the orphaned pending logs.
+    pub fn roll_back_to(&mut self, ancestor: Nat, ancestor_hash: String) -> Vec<LogSource> {
+        let is_kept = |entry: &LogEntry| entry.blockNumber.as_ref().is_some_and(|number| *number <= ancestor);
+        let (kept, orphaned): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.pending_logs)
+            .into_iter()
+            .partition(|(_, entry)| is_kept(entry));
+        self.pending_logs = kept;
+        self.block_hashes.retain(|number, _| *number < ancestor);
+        self.skipped_blocks.retain(|number| *number <= ancestor);
+        if self.last_scraped_block_number > ancestor {
+            self.last_scraped_block_number = ancestor.clone();
+        }
+        self.block_hashes.insert(ancestor, ancestor_hash);
+        orphaned.into_keys().collect()
+    }
+
+    /// The deposits found in the pending logs, as seen from `chain_id`.
+    pub fn pending_deposits(&self, chain_id: u32) -> Vec<PendingDeposit> {
+        let head = self.latest_block_number.as_ref().unwrap_or(&self.last_scraped_block_number);
+        self.pending_logs
+            .iter()
+            .filter_map(|(source, entry)| {
+                let (recipient, asset, amount) = match Event::try_from(entry.clone()).ok()? {
+                    Event::DepositEth(event) => (event.recipient, AssetId::Native { chain_id }, event.amount),
+                    Event::DepositErc20(event) => {
+                        (event.recipient, AssetId::Erc20 { chain_id, token: event.token.into() }, event.amount)
+                    }
+                };
+                let block_number = entry.blockNumber.clone()?;
+                let confirmations = if *head < block_number {
+                    Nat::from(0u32)
+                } else {
+                    head.clone() - block_number.clone() + Nat::from(1u32)
+                };
+                Some(PendingDeposit {
+                    recipient,
+                    asset,
+                    amount: amount.into(),
+                    source: source.clone(),
+                    block_number,
+                    confirmations,
+                    required_confirmations: self.confirmations,
+                })
+            })
+            .collect()
     }
 
     pub fn record_log_to_process(&mut self, log_entry: &LogEntry) {
@@ -129,36 +302,166 @@ impl Network {
     }
 }
 
-impl From<NetworkInit> for Network {
-    fn from(init: NetworkInit) -> Self {
+impl Network {
+    pub fn new(network_id: u32, init: NetworkInit) -> Self {
         Network {
             rpc_services: init.rpc_services,
             rpc_service: init.rpc_service,
             last_scraped_block_number: init.last_scraped_block_number,
             last_observed_block_number: None,
+            latest_block_number: None,
             last_processed_block_number: None,
             logs_to_process: Default::default(),
             get_logs_address: init.get_logs_address.into_iter().map(|a| a.into()).collect(),
-            processed_logs: Default::default(),
+            get_logs_topics: init.get_logs_topics.unwrap_or_else(default_get_logs_topics),
+            processed_logs: ProcessedLogs::new(network_id),
             skipped_blocks: Default::default(),
             nonce: Default::default(),
             block_tag: init.block_tag,
+            confirmations: init.confirmations.unwrap_or_default(),
+            pending_logs: Default::default(),
+            block_hashes: Default::default(),
+            max_block_spread: init.max_block_spread.unwrap_or(DEFAULT_MAX_BLOCK_SPREAD),
+            block_spread: init.max_block_spread.unwrap_or(DEFAULT_MAX_BLOCK_SPREAD),
         }
     }
 }
 
+/// The key of a processed log: the network it was scraped from and its source.
+#[derive(CandidType, Deserialize)]
+struct ProcessedLogKey {
+    network_id: u32,
+    source: LogSource,
+}
+
+impl Storable for ProcessedLogKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("BUG: failed to encode a processed log key"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("BUG: failed to decode a processed log key")
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("BUG: failed to encode a log entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("BUG: failed to decode a log entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    /// The processed logs of every network, which live in their own stable memory
+    /// since they grow without bound.
+    static PROCESSED_LOG_ENTRIES: RefCell<StableBTreeMap<ProcessedLogKey, LogEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(get_memory(PROCESSED_LOGS))
+    );
+}
+
+/// The processed logs of a network, kept in stable memory.
+#[derive(Debug, Clone)]
+pub struct ProcessedLogs {
+    network_id: u32,
+}
+
+impl ProcessedLogs {
+    pub fn new(network_id: u32) -> Self {
+        ProcessedLogs { network_id }
+    }
+
+    pub fn contains_key(&self, source: &LogSource) -> bool {
+        let key = ProcessedLogKey { network_id: self.network_id, source: source.clone() };
+        PROCESSED_LOG_ENTRIES.with(|logs| logs.borrow().contains_key(&key))
+    }
+
+    /// Records a processed log and returns the entry previously recorded for its source.
+    pub fn insert(&mut self, source: LogSource, log_entry: LogEntry) -> Option<LogEntry> {
+        let key = ProcessedLogKey { network_id: self.network_id, source };
+        PROCESSED_LOG_ENTRIES.with(|logs| logs.borrow_mut().insert(key, log_entry))
+    }
+}
+
+/// The scraping progress of a network.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct NetworkStatus {
+    pub last_scraped_block_number: Nat,
+    pub last_observed_block_number: Option<Nat>,
+    pub latest_block_number: Option<Nat>,
+    pub last_processed_block_number: Option<Nat>,
+    pub block_spread: u64,
+    pub max_block_spread: u64,
+}
+
+/// A deposit whose block is not final yet.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PendingDeposit {
+    pub recipient: Account,
+    /// The asset as deposited on chain.
+    pub asset: AssetId,
+    pub amount: U256t,
+    pub source: LogSource,
+    pub block_number: Nat,
+    /// The number of blocks up to the head of the chain, the block of the deposit included.
+    pub confirmations: Nat,
+    /// The confirmations needed before the deposit is credited, on top of the block tag reaching it.
+    pub required_confirmations: u64,
+}
+
 pub struct State {
-    pub owner: Principal,
-    pub wallets: Wallets<Principal>,
+    pub roles: Roles,
+    /// The principal whose default wallet collects the protocol fees.
+    pub treasury: Principal,
+    pub wallets: Wallets<Account>,
     pub networks: HashMap<u32, Network>,
-
-    pub active_tasks: HashSet<TaskType>,
-    pub get_logs_topics: Option<Vec<Vec<String>>>,
+    pub history: History,
+    pub tokens: Tokens,
+    pub dedup: Deduplication,
+    pub canonical_assets: CanonicalAssets,
+    pub fees: FeeSchedule,
+    pub rate_limits: RateLimits,
+    pub pauses: Pauses,
+    pub timelock: Timelock,
+    pub compliance: Compliance,
+    pub settlements: Settlements,
+    /// How many networks may scrape logs at the same time.
+    pub max_concurrent_scrapes: u32,
+
+    pub active_tasks: HashSet<(TaskType, u32)>,
+    pub scraping_timers: HashMap<u32, TimerId>,
     pub ecdsa_pub_key: Option<Vec<u8>>,
     pub ecdsa_key_id: EcdsaKeyId,
     pub evm_address: Option<H160>,
 }
 
+impl State {
+    /// Records a balance change in the history of the affected wallets and returns its id.
+    pub fn record_transaction(&mut self, entries: impl IntoIterator<Item=(Account, TransactionKind)>) -> u64 {
+        self.history.record(ic_cdk::api::time(), entries)
+    }
+
+    /// Updates the configuration of a network, or adds the network if it does not exist yet.
+    pub fn apply_network_config(&mut self, network_id: u32, network_mut: NetworkMut) {
+        if let Some(entry) = self.networks.get_mut(&network_id) {
+            entry.mutate_with(network_mut);
+        } else {
+            let new_network = network_mut.into_init().expect("BUG: network config is missing fields required for initialization");
+            self.networks.insert(network_id, Network::new(network_id, new_network));
+        }
+    }
+
+    pub fn treasury_account(&self) -> Account {
+        Account::new(self.treasury, None)
+    }
+}
+
 #[derive(CandidType, Deserialize, Debug)]
 pub struct Init {
     environment: String,
@@ -172,20 +475,36 @@ impl From<Init> for State {
         let networks = init
             .networks
             .into_iter()
-            .map(|(id, init)| (id, init.into()))
+            .map(|(id, init)| {
+                check_get_logs_topics(init.get_logs_topics.as_ref());
+                (id, Network::new(id, init))
+            })
             .collect();
 
-        let get_logs_topics = Some(vec![
-            vec![DepositEthEvent::topic()],
-            vec![DepositErc20Event::topic()],
-        ]);
+        // The initial owner starts with every role and can hand them out from there
+        let mut roles = Roles::new();
+        for role in Role::ALL {
+            roles.grant(init.initial_owner, role);
+        }
 
         State {
-            owner: init.initial_owner,
+            roles,
+            treasury: init.initial_owner,
             wallets: Wallets::new(),
             networks,
-            get_logs_topics,
+            history: History::new(),
+            tokens: Tokens::new(),
+            dedup: Deduplication::new(),
+            canonical_assets: CanonicalAssets::new(),
+            fees: FeeSchedule::new(),
+            rate_limits: RateLimits::new(),
+            pauses: Pauses::new(),
+            timelock: Timelock::new(),
+            compliance: Compliance::new(),
+            settlements: Settlements::new(),
+            max_concurrent_scrapes: DEFAULT_MAX_CONCURRENT_SCRAPES,
             active_tasks: Default::default(),
+            scraping_timers: Default::default(),
             ecdsa_key_id: init.ecdsa_key_id,
             ecdsa_pub_key: None,
             evm_address: None,
@@ -193,6 +512,35 @@ impl From<Init> for State {
     }
 }
 
+/// Matches the logs whose `topic0` is one of the registered events.
+pub fn default_get_logs_topics() -> Vec<Vec<String>> {
+    vec![Event::topics()]
+}
+
+/// Whether the topic filters of `eth_getLogs` only let through registered events, with
+/// well-formed topics in the later positions.
+pub fn is_valid_get_logs_topics(topics: &[Vec<String>]) -> bool {
+    let registered = Event::topics();
+    let is_topic = |topic: &String| {
+        topic.len() == 66 && topic.starts_with("0x") && topic[2..].chars().all(|c| c.is_ascii_hexdigit())
+    };
+    match topics.split_first() {
+        Some((topic0, rest)) => {
+            !topic0.is_empty()
+                && topic0.iter().all(|topic| registered.contains(&topic.to_lowercase()))
+                && rest.iter().flatten().all(is_topic)
+        }
+        None => false,
+    }
+}
+
+/// Traps if `get_logs_topics` would filter out deposits or let foreign logs through.
+pub fn check_get_logs_topics(get_logs_topics: Option<&Vec<Vec<String>>>) {
+    if get_logs_topics.is_some_and(|topics| !is_valid_get_logs_topics(topics)) {
+        ic_cdk::trap("The topic0 filters of the logs must be a non-empty subset of the registered events.");
+    }
+}
+
 thread_local! {
     pub static STATE: RefCell<Option<State>> = RefCell::default();
 }
@@ -229,10 +577,6 @@ pub fn initialize_state(state: State) {
     STATE.set(Some(state));
 }
 
-pub fn caller_is_owner() -> bool {
-    read_state(|s| s.owner == ic_cdk::caller())
-}
-
 pub async fn get_public_key() -> Vec<u8> {
     let key_id = read_state(|s| s.ecdsa_key_id.clone());
     let key = ecdsa::get_public_key(key_id).await.unwrap();
@@ -241,32 +585,68 @@ pub async fn get_public_key() -> Vec<u8> {
 
 // Public API
 
+/// Hands all roles of the caller over to `new_owner`.
 pub fn set_owner(new_owner: Principal) {
-    if !caller_is_owner() {
-        ic_cdk::trap("Only the owner can assign a new owner.");
-    }
+    require_role(Role::Admin);
+    let caller = ic_cdk::caller();
     mutate_state(|s| {
-        s.owner = new_owner;
+        let roles = s.roles.of(&caller);
+        for role in roles.iter() {
+            s.roles.grant(new_owner, *role);
+        }
+        if new_owner != caller {
+            s.roles.grants.remove(&caller);
+        }
     });
+    audit::record_change("set_owner", (new_owner,), caller, new_owner);
 }
 
-pub fn get_owner() -> Principal {
-    read_state(|s| s.owner)
+pub fn set_treasury(treasury: Principal) {
+    require_role(Role::Admin);
+    let old_treasury = mutate_state(|s| std::mem::replace(&mut s.treasury, treasury));
+    audit::record_change("set_treasury", (treasury,), old_treasury, treasury);
 }
 
+/// Sets the fee of `operation` on `asset`, or removes it if `fee` is `None`.
+pub fn set_fee(operation: Operation, asset: AssetId, fee: Option<Fee>) -> Result<(), HarmonizeError> {
+    require_role(Role::Admin);
+    let old_fee = mutate_state(|s| -> Result<_, FeeError> {
+        let old_fee = s.fees.fees.get(&(operation, asset)).copied();
+        s.fees.set(operation, asset, fee)?;
+        Ok(old_fee)
+    })?;
+    audit::record_change("set_fee", (operation, asset, fee), old_fee, fee);
+    Ok(())
+}
+
+/// Applies a network configuration right away, which is only allowed while changes
+/// are not time-locked. Otherwise they go through [`crate::timelock`].
 pub fn set_network_config(chain_id: u32, network_mut: NetworkMut) {
-    if !caller_is_owner() {
-        ic_cdk::trap("Only the owner can change the network configuration.");
+    require_role(Role::Operator);
+    if read_state(|s| s.timelock.delay_at(ic_cdk::api::time())) > 0 {
+        ic_cdk::trap("Network configuration changes are time-locked, propose them instead.");
     }
-    mutate_state(|s| {
-        let network_id = chain_id;
-        if let Some(entry) = s.networks.get_mut(&network_id) {
-            entry.mutate_with(network_mut);
-        } else {
-            let new_network = network_mut.into_init().expect("BUG: network config is missing fields required for initialization");
-            s.networks.insert(network_id, new_network.into());
-        }
+    check_get_logs_topics(network_mut.get_logs_topics.as_ref());
+    let (old_network, new_network) = mutate_state(|s| {
+        let old_network = s.networks.get(&chain_id).map(Network::config);
+        s.apply_network_config(chain_id, network_mut.clone());
+        (old_network, s.networks.get(&chain_id).map(Network::config))
     });
+    audit::record_change("set_network_config", (chain_id, network_mut), old_network, new_network);
+    chain_fusion::start_scraping(chain_id);
+}
+
+pub fn set_max_concurrent_scrapes(max_concurrent_scrapes: u32) {
+    require_role(Role::Operator);
+    if max_concurrent_scrapes == 0 {
+        ic_cdk::trap("At least one network must be able to scrape logs, pause scraping instead.");
+    }
+    let old_max = mutate_state(|s| std::mem::replace(&mut s.max_concurrent_scrapes, max_concurrent_scrapes));
+    audit::record_change("set_max_concurrent_scrapes", (max_concurrent_scrapes,), old_max, max_concurrent_scrapes);
+}
+
+pub fn get_max_concurrent_scrapes() -> u32 {
+    read_state(|s| s.max_concurrent_scrapes)
 }
 
 pub fn get_ethereum_address() -> H160 {
@@ -285,8 +665,90 @@ pub fn get_last_scraped_block(chain_id: u32) -> Nat {
     read_network_state(chain_id, |n| n.last_scraped_block_number.clone())
 }
 
+pub fn get_network_status(chain_id: u32) -> NetworkStatus {
+    read_network_state(chain_id, |n| NetworkStatus {
+        last_scraped_block_number: n.last_scraped_block_number.clone(),
+        last_observed_block_number: n.last_observed_block_number.clone(),
+        latest_block_number: n.latest_block_number.clone(),
+        last_processed_block_number: n.last_processed_block_number.clone(),
+        block_spread: n.block_spread,
+        max_block_spread: n.max_block_spread,
+    })
+}
+
+/// Lists the deposits to `account` on every network that are not credited yet because their block is not final.
+pub fn get_pending_deposits(account: Account) -> Vec<PendingDeposit> {
+    read_state(|s| {
+        s.networks
+            .iter()
+            .flat_map(|(chain_id, network)| network.pending_deposits(*chain_id))
+            .filter(|deposit| deposit.recipient == account)
+            .collect()
+    })
+}
+
 pub fn get_last_processed_block(chain_id: u32) -> Nat {
     read_network_state(chain_id, |n| {
         n.last_processed_block_number.clone().unwrap_or(Nat::from(0u32))
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_in_block(number: u32, hash: &str) -> LogEntry {
+        LogEntry {
+            transactionHash: Some(format!("0x{number}")),
+            blockNumber: Some(Nat::from(number)),
+            data: String::new(),
+            blockHash: Some(hash.to_string()),
+            transactionIndex: Some(Nat::from(0u32)),
+            topics: vec![],
+            address: String::new(),
+            logIndex: Some(Nat::from(0u32)),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn test_pending_logs_wait_for_confirmations_and_roll_back() {
+        let mut network = Network::new(1, NetworkInit {
+            rpc_services: RpcServices::EthSepolia(None),
+            rpc_service: RpcService::Chain(1),
+            last_scraped_block_number: Nat::from(12u32),
+            get_logs_address: vec![],
+            block_tag: BlockTag::Latest,
+            confirmations: Some(2),
+            max_block_spread: None,
+            get_logs_topics: None,
+        });
+        for (number, hash) in [(10, "a"), (11, "b"), (12, "c")] {
+            network.record_pending_log(&log_in_block(number, hash));
+        }
+
+        network.confirm_pending_logs(&Nat::from(12u32));
+        assert_eq!(network.logs_to_process.len(), 1);
+        assert_eq!(network.pending_logs.len(), 2);
+        assert_eq!(network.block_hashes.keys().cloned().collect::<Vec<_>>(), vec![Nat::from(10u32), Nat::from(11u32), Nat::from(12u32)]);
+
+        // Block 12 was reorged out, block 11 is still part of the chain
+        let orphaned = network.roll_back_to(Nat::from(11u32), "b".to_string());
+        assert_eq!(orphaned, vec![log_in_block(12, "c").source()]);
+        assert_eq!(network.pending_logs.len(), 1);
+        assert_eq!(network.last_scraped_block_number, Nat::from(11u32));
+        assert_eq!(network.block_hashes.last_key_value(), Some((&Nat::from(11u32), &"b".to_string())));
+    }
+
+    #[test]
+    fn test_get_logs_topics_only_match_registered_events() {
+        // Logs are filtered by the topic0 of the registered events by default
+        assert!(is_valid_get_logs_topics(&default_get_logs_topics()));
+        assert!(is_valid_get_logs_topics(&[Event::topics()[..1].to_vec(), vec![format!("0x{}", "0".repeat(64))]]));
+
+        assert!(!is_valid_get_logs_topics(&[]));
+        assert!(!is_valid_get_logs_topics(&[vec![]]));
+        assert!(!is_valid_get_logs_topics(&[vec![format!("0x{}", "0".repeat(64))]]));
+        assert!(!is_valid_get_logs_topics(&[Event::topics(), vec!["0x1".to_string()]]));
+    }
+}
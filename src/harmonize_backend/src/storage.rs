@@ -0,0 +1,468 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    writer::Writer,
+    DefaultMemoryImpl,
+    Memory as _,
+};
+
+use crate::access_control::{Role, Roles};
+use crate::canonical::{CanonicalAsset, CanonicalAssets};
+use crate::compliance::{Compliance, QuarantinedDeposit};
+use crate::chain_fusion::evm_rpc::{BlockTag, LogEntry, RpcService, RpcServices};
+use crate::chain_fusion::eth_get_logs::DEFAULT_MAX_BLOCK_SPREAD;
+use crate::chain_fusion::{LogSource, DEFAULT_MAX_CONCURRENT_SCRAPES};
+use crate::dedup::{Call, DedupKey, Deduplication, Outcome};
+use crate::fees::{Fee, FeeSchedule, Operation};
+use crate::history::{History, Transaction};
+use crate::limits::{LimitScope, RateLimits, RecentWithdrawal, WithdrawalLimit};
+use crate::pause::{PauseFlag, Pauses};
+use crate::settlement::{PendingTransaction, Settlements};
+use crate::timelock::{NetworkProposal, PendingDelay, Timelock};
+use crate::state::{self, read_state, Init, Network, ProcessedLogs, State};
+use crate::tokens::{HeldDeposit, TokenMetadata, Tokens};
+use crate::types::{H160t, U256t};
+use crate::wallet::{Account, Allowance, AssetId, Wallet, Wallets};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// The memory holding the serialized state between `pre_upgrade` and `post_upgrade`.
+const UPGRADES: MemoryId = MemoryId::new(0);
+/// The memories holding the index and the entries of the audit log.
+pub const AUDIT_LOG_INDEX: MemoryId = MemoryId::new(1);
+pub const AUDIT_LOG_DATA: MemoryId = MemoryId::new(2);
+/// The memories holding the transaction history and the number of transactions of each wallet.
+pub const HISTORY_TRANSACTIONS: MemoryId = MemoryId::new(3);
+pub const HISTORY_COUNTS: MemoryId = MemoryId::new(4);
+/// The memory holding the processed logs of every network.
+pub const PROCESSED_LOGS: MemoryId = MemoryId::new(5);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Serializes the current state into stable memory.
+///
+/// The layout is the length of the encoded state as a little endian `u64`,
+/// followed by the candid encoded [`StableState`].
+pub fn save_state() {
+    let bytes = read_state(|s| candid::encode_one(StableState::from(s)))
+        .expect("BUG: failed to encode the state");
+    let mut memory = get_memory(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .expect("BUG: failed to write the state length");
+    writer.write(&bytes).expect("BUG: failed to write the state");
+}
+
+/// Restores the state written by [`save_state`].
+///
+/// If no state was written, e.g. when upgrading a canister that never went
+/// through [`save_state`], the state is initialized from `init` instead.
+pub fn restore_state(init: Option<Init>) {
+    let memory = get_memory(UPGRADES);
+    let mut len_bytes = [0; 8];
+    if memory.size() > 0 {
+        memory.read(0, &mut len_bytes);
+    }
+    let len = u64::from_le_bytes(len_bytes) as usize;
+    if len == 0 {
+        let init = init.unwrap_or_else(|| ic_cdk::trap("no state to restore, the upgrade requires init arguments"));
+        state::initialize_state(init.into());
+        return;
+    }
+    let mut bytes = vec![0; len];
+    memory.read(8, &mut bytes);
+    let stable_state: StableState = candid::decode_one(&bytes)
+        .expect("BUG: failed to decode the state");
+    state::initialize_state(stable_state.into());
+}
+
+/// The part of [`State`] that survives upgrades.
+///
+/// Runtime bookkeeping such as the active tasks is not persisted, since timers
+/// and in-flight calls do not survive an upgrade either. The transaction history
+/// and the processed logs live in their own stable memories.
+///
+/// The state written by an older version must keep decoding: fields added to
+/// the layout are optional and fall back to their default when missing, and
+/// fields that are no longer written are kept as optional to migrate them.
+#[derive(CandidType, Deserialize)]
+pub struct StableState {
+    /// The owner of layouts that predate roles, who is granted every role.
+    pub owner: Option<Principal>,
+    pub roles: Option<Vec<(Principal, Vec<Role>)>>,
+    pub treasury: Option<Principal>,
+    pub wallets: Vec<StableWallet>,
+    pub allowances: Option<Vec<(Account, Account, AssetId, Allowance)>>,
+    pub networks: HashMap<u32, StableNetwork>,
+    pub next_transaction_id: Option<u64>,
+    /// The history of layouts that kept it in the snapshot.
+    pub transactions: Option<HashMap<Account, Vec<Transaction>>>,
+    pub tokens: Option<Vec<(u32, H160t, TokenMetadata)>>,
+    pub held_deposits: Option<Vec<HeldDeposit>>,
+    pub dedup: Option<Vec<(DedupKey, Outcome)>>,
+    pub canonical_assets: Option<Vec<CanonicalAsset>>,
+    pub fees: Option<Vec<(Operation, AssetId, Fee)>>,
+    pub withdrawal_limits: Option<Vec<(AssetId, LimitScope, WithdrawalLimit)>>,
+    pub recent_withdrawals: Option<Vec<RecentWithdrawal>>,
+    pub paused: Option<Vec<PauseFlag>>,
+    pub network_config_delay: Option<u64>,
+    pub pending_network_config_delay: Option<PendingDelay>,
+    pub next_proposal_id: Option<u64>,
+    pub network_proposals: Option<Vec<NetworkProposal>>,
+    pub frozen_principals: Option<Vec<Principal>>,
+    pub blocked_addresses: Option<Vec<H160t>>,
+    pub quarantined_deposits: Option<Vec<QuarantinedDeposit>>,
+    pub pending_transactions: Option<Vec<PendingTransaction>>,
+    pub max_concurrent_scrapes: Option<u32>,
+    pub ecdsa_key_id: EcdsaKeyId,
+    pub ecdsa_pub_key: Option<Vec<u8>>,
+    pub evm_address: Option<H160t>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableWallet {
+    pub account: Option<Account>,
+    pub balances: Option<Vec<(AssetId, U256t)>>,
+    pub held: Option<Vec<(AssetId, U256t)>>,
+    /// The owner and balances of layouts that predate accounts and assets.
+    pub owner: Option<Principal>,
+    pub eth: Option<Vec<(u32, U256t)>>,
+    pub erc20: Option<Vec<(u32, H160t, U256t)>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableNetwork {
+    pub rpc_services: RpcServices,
+    pub rpc_service: RpcService,
+    pub last_scraped_block_number: Nat,
+    pub last_observed_block_number: Option<Nat>,
+    pub latest_block_number: Option<Nat>,
+    pub last_processed_block_number: Option<Nat>,
+    pub logs_to_process: BTreeMap<LogSource, LogEntry>,
+    pub get_logs_address: Vec<H160t>,
+    pub get_logs_topics: Option<Vec<Vec<String>>>,
+    /// The processed logs of layouts that kept them in the snapshot.
+    pub processed_logs: Option<BTreeMap<LogSource, LogEntry>>,
+    pub skipped_blocks: BTreeSet<Nat>,
+    pub block_tag: BlockTag,
+    pub nonce: U256t,
+    pub confirmations: Option<u64>,
+    pub pending_logs: Option<BTreeMap<LogSource, LogEntry>>,
+    pub block_hashes: Option<BTreeMap<Nat, String>>,
+    pub max_block_spread: Option<u64>,
+    pub block_spread: Option<u64>,
+}
+
+impl From<&State> for StableState {
+    fn from(state: &State) -> Self {
+        StableState {
+            owner: None,
+            roles: Some(
+                state
+                    .roles
+                    .grants
+                    .iter()
+                    .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+                    .collect(),
+            ),
+            treasury: Some(state.treasury),
+            wallets: state.wallets.wallets.values().map(Into::into).collect(),
+            allowances: Some(
+                state
+                    .wallets
+                    .allowances
+                    .iter()
+                    .map(|((owner, spender, asset), allowance)| (*owner, *spender, *asset, allowance.clone()))
+                    .collect(),
+            ),
+            networks: state
+                .networks
+                .iter()
+                .map(|(id, network)| (*id, network.into()))
+                .collect(),
+            next_transaction_id: Some(state.history.next_id),
+            transactions: None,
+            tokens: Some(
+                state
+                    .tokens
+                    .tokens
+                    .iter()
+                    .map(|((chain_id, token), metadata)| (*chain_id, (*token).into(), metadata.clone()))
+                    .collect(),
+            ),
+            held_deposits: Some(state.tokens.held_deposits.clone()),
+            dedup: Some(
+                state
+                    .dedup
+                    .calls
+                    .iter()
+                    .filter_map(|(key, call)| match call {
+                        Call::Completed(outcome) => Some((key.clone(), outcome.clone())),
+                        // Calls in flight do not survive an upgrade
+                        Call::InFlight { .. } => None,
+                    })
+                    .collect(),
+            ),
+            canonical_assets: Some(state.canonical_assets.assets.values().cloned().collect()),
+            fees: Some(
+                state
+                    .fees
+                    .fees
+                    .iter()
+                    .map(|((operation, asset), fee)| (*operation, *asset, *fee))
+                    .collect(),
+            ),
+            withdrawal_limits: Some(
+                state
+                    .rate_limits
+                    .limits
+                    .iter()
+                    .map(|((asset, scope), limit)| (*asset, *scope, *limit))
+                    .collect(),
+            ),
+            recent_withdrawals: Some(state.rate_limits.withdrawals.iter().cloned().collect()),
+            paused: Some(state.pauses.flags.iter().copied().collect()),
+            network_config_delay: Some(state.timelock.delay_nanos),
+            pending_network_config_delay: state.timelock.pending_delay,
+            next_proposal_id: Some(state.timelock.next_id),
+            network_proposals: Some(state.timelock.proposals.values().cloned().collect()),
+            frozen_principals: Some(state.compliance.frozen.iter().copied().collect()),
+            blocked_addresses: Some(state.compliance.blocked.iter().map(|address| (*address).into()).collect()),
+            quarantined_deposits: Some(state.compliance.quarantined.clone()),
+            pending_transactions: Some(state.settlements.pending.values().cloned().collect()),
+            max_concurrent_scrapes: Some(state.max_concurrent_scrapes),
+            ecdsa_key_id: state.ecdsa_key_id.clone(),
+            ecdsa_pub_key: state.ecdsa_pub_key.clone(),
+            evm_address: state.evm_address.map(Into::into),
+        }
+    }
+}
+
+impl From<StableState> for State {
+    fn from(stable: StableState) -> Self {
+        let mut wallets = Wallets::new();
+        for wallet in stable.wallets {
+            let account = wallet
+                .account
+                .or(wallet.owner.map(|owner| Account::new(owner, None)))
+                .expect("BUG: a wallet has neither an account nor an owner");
+            let legacy_balances = wallet
+                .eth
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(chain_id, amount)| (AssetId::Native { chain_id }, amount))
+                .chain(
+                    wallet
+                        .erc20
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(chain_id, token, amount)| (AssetId::Erc20 { chain_id, token }, amount)),
+                );
+            for (asset, amount) in wallet.balances.unwrap_or_default().into_iter().chain(legacy_balances) {
+                wallets
+                    .credit_asset(account, &asset, amount.into())
+                    .expect("BUG: failed to restore a balance");
+            }
+            for (asset, amount) in wallet.held.unwrap_or_default() {
+                wallets
+                    .credit_asset(account, &asset, amount.into())
+                    .and_then(|_| wallets.hold_asset(account, &asset, amount.into()))
+                    .expect("BUG: failed to restore a held balance");
+            }
+        }
+        for (owner, spender, asset, allowance) in stable.allowances.unwrap_or_default() {
+            wallets.allowances.insert((owner, spender, asset), allowance);
+        }
+
+        let roles = match stable.roles {
+            Some(grants) => Roles {
+                grants: grants
+                    .into_iter()
+                    .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+                    .collect(),
+            },
+            None => {
+                let owner = stable.owner.expect("BUG: the state has neither roles nor an owner");
+                let mut roles = Roles::new();
+                for role in Role::ALL {
+                    roles.grant(owner, role);
+                }
+                roles
+            }
+        };
+
+        let mut history = History { next_id: stable.next_transaction_id.unwrap_or_default() };
+        for (wallet, transactions) in stable.transactions.unwrap_or_default() {
+            history.import(wallet, transactions);
+        }
+
+        State {
+            roles,
+            treasury: stable
+                .treasury
+                .or(stable.owner)
+                .expect("BUG: the state has neither a treasury nor an owner"),
+            wallets,
+            networks: stable
+                .networks
+                .into_iter()
+                .map(|(id, network)| (id, network.into_network(id)))
+                .collect(),
+            history,
+            tokens: Tokens {
+                tokens: stable
+                    .tokens
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(chain_id, token, metadata)| ((chain_id, token.into()), metadata))
+                    .collect(),
+                held_deposits: stable.held_deposits.unwrap_or_default(),
+            },
+            dedup: Deduplication {
+                calls: stable
+                    .dedup
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(key, outcome)| (key, Call::Completed(outcome)))
+                    .collect(),
+            },
+            canonical_assets: CanonicalAssets {
+                assets: stable
+                    .canonical_assets
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|asset| (asset.id, asset))
+                    .collect(),
+            },
+            fees: FeeSchedule {
+                fees: stable
+                    .fees
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(operation, asset, fee)| ((operation, asset), fee))
+                    .collect(),
+            },
+            rate_limits: RateLimits {
+                limits: stable
+                    .withdrawal_limits
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(asset, scope, limit)| ((asset, scope), limit))
+                    .collect(),
+                withdrawals: stable.recent_withdrawals.unwrap_or_default().into(),
+            },
+            pauses: Pauses {
+                flags: stable.paused.unwrap_or_default().into_iter().collect(),
+            },
+            timelock: Timelock {
+                delay_nanos: stable.network_config_delay.unwrap_or_default(),
+                pending_delay: stable.pending_network_config_delay,
+                next_id: stable.next_proposal_id.unwrap_or_default(),
+                proposals: stable
+                    .network_proposals
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|proposal| (proposal.id, proposal))
+                    .collect(),
+            },
+            compliance: Compliance {
+                frozen: stable.frozen_principals.unwrap_or_default().into_iter().collect(),
+                blocked: stable.blocked_addresses.unwrap_or_default().into_iter().map(|address| address.0).collect(),
+                quarantined: stable.quarantined_deposits.unwrap_or_default(),
+            },
+            settlements: Settlements {
+                pending: stable
+                    .pending_transactions
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|pending| (pending.tx_hash.clone(), pending))
+                    .collect(),
+            },
+            max_concurrent_scrapes: stable.max_concurrent_scrapes.unwrap_or(DEFAULT_MAX_CONCURRENT_SCRAPES),
+            active_tasks: Default::default(),
+            scraping_timers: Default::default(),
+            ecdsa_pub_key: stable.ecdsa_pub_key,
+            ecdsa_key_id: stable.ecdsa_key_id,
+            evm_address: stable.evm_address.map(Into::into),
+        }
+    }
+}
+
+impl From<&Wallet<Account>> for StableWallet {
+    fn from(wallet: &Wallet<Account>) -> Self {
+        StableWallet {
+            account: Some(wallet.id),
+            balances: Some(wallet.iter().map(|(asset, amount)| (*asset, (*amount).into())).collect()),
+            held: Some(wallet.iter_held().map(|(asset, amount)| (*asset, (*amount).into())).collect()),
+            owner: None,
+            eth: None,
+            erc20: None,
+        }
+    }
+}
+
+impl From<&Network> for StableNetwork {
+    fn from(network: &Network) -> Self {
+        StableNetwork {
+            rpc_services: network.rpc_services.clone(),
+            rpc_service: network.rpc_service.clone(),
+            last_scraped_block_number: network.last_scraped_block_number.clone(),
+            last_observed_block_number: network.last_observed_block_number.clone(),
+            latest_block_number: network.latest_block_number.clone(),
+            last_processed_block_number: network.last_processed_block_number.clone(),
+            logs_to_process: network.logs_to_process.clone(),
+            get_logs_address: network.get_logs_address.iter().map(|a| (*a).into()).collect(),
+            get_logs_topics: Some(network.get_logs_topics.clone()),
+            processed_logs: None,
+            skipped_blocks: network.skipped_blocks.clone(),
+            block_tag: network.block_tag.clone(),
+            nonce: network.nonce.into(),
+            confirmations: Some(network.confirmations),
+            pending_logs: Some(network.pending_logs.clone()),
+            block_hashes: Some(network.block_hashes.clone()),
+            max_block_spread: Some(network.max_block_spread),
+            block_spread: Some(network.block_spread),
+        }
+    }
+}
+
+impl StableNetwork {
+    fn into_network(self, network_id: u32) -> Network {
+        let mut processed_logs = ProcessedLogs::new(network_id);
+        for (source, log_entry) in self.processed_logs.unwrap_or_default() {
+            processed_logs.insert(source, log_entry);
+        }
+        let max_block_spread = self.max_block_spread.unwrap_or(DEFAULT_MAX_BLOCK_SPREAD);
+        Network {
+            rpc_services: self.rpc_services,
+            rpc_service: self.rpc_service,
+            last_scraped_block_number: self.last_scraped_block_number,
+            last_observed_block_number: self.last_observed_block_number,
+            latest_block_number: self.latest_block_number,
+            last_processed_block_number: self.last_processed_block_number,
+            logs_to_process: self.logs_to_process,
+            get_logs_address: self.get_logs_address.into_iter().map(Into::into).collect(),
+            get_logs_topics: self.get_logs_topics.unwrap_or_else(state::default_get_logs_topics),
+            processed_logs,
+            skipped_blocks: self.skipped_blocks,
+            block_tag: self.block_tag,
+            nonce: self.nonce.into(),
+            confirmations: self.confirmations.unwrap_or_default(),
+            pending_logs: self.pending_logs.unwrap_or_default(),
+            block_hashes: self.block_hashes.unwrap_or_default(),
+            max_block_spread,
+            block_spread: self.block_spread.unwrap_or(max_block_spread),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Time-locked network configuration changes.
+//!
+//! A change is proposed first and can only be executed once the delay has
+//! passed, which leaves time to spot and cancel a mistaken or malicious one.
+
+use std::collections::BTreeMap;
+use candid::{CandidType, Deserialize, Principal};
+use thiserror::Error;
+
+use crate::{
+    access_control::{require_any_role, require_role, Role},
+    audit,
+    chain_fusion,
+    state::{self, mutate_state, read_state, Network, NetworkMut},
+    HarmonizeError,
+};
+
+#[derive(Error, Debug, CandidType)]
+pub enum TimelockError {
+    #[error("The proposal does not exist")]
+    NotFound,
+    #[error("The proposal can only be executed at {executable_at}")]
+    NotReady { executable_at: u64 },
+    #[error("The network does not exist yet and the proposal misses fields required to add it")]
+    IncompleteNetwork,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct NetworkProposal {
+    pub id: u64,
+    pub chain_id: u32,
+    pub change: NetworkMut,
+    pub proposer: Principal,
+    /// Nanoseconds since the UNIX epoch.
+    pub proposed_at: u64,
+    pub executable_at: u64,
+}
+
+/// A reduction of the delay, which only takes effect once the previous delay has passed.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PendingDelay {
+    pub delay_nanos: u64,
+    /// Nanoseconds since the UNIX epoch.
+    pub effective_at: u64,
+}
+
+#[derive(Default)]
+pub struct Timelock {
+    /// How long a proposal waits before it can be executed, in nanoseconds.
+    /// Changes are applied directly while it is zero.
+    pub delay_nanos: u64,
+    pub pending_delay: Option<PendingDelay>,
+    pub next_id: u64,
+    pub proposals: BTreeMap<u64, NetworkProposal>,
+}
+
+impl Timelock {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the delay in effect at `now`.
+    pub fn delay_at(&self, now: u64) -> u64 {
+        match self.pending_delay {
+            Some(pending) if pending.effective_at <= now => pending.delay_nanos,
+            _ => self.delay_nanos,
+        }
+    }
+
+    /// Raises the delay right away. A reduction is itself time-locked by the current
+    /// delay, so that it cannot be used to skip the timelock.
+    pub fn set_delay(&mut self, delay_nanos: u64, now: u64) {
+        let current = self.delay_at(now);
+        self.delay_nanos = current.max(delay_nanos);
+        self.pending_delay = (delay_nanos < current).then_some(PendingDelay {
+            delay_nanos,
+            effective_at: now.saturating_add(current),
+        });
+    }
+
+    pub fn propose(&mut self, chain_id: u32, change: NetworkMut, proposer: Principal, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let executable_at = now.saturating_add(self.delay_at(now));
+        self.proposals.insert(id, NetworkProposal { id, chain_id, change, proposer, proposed_at: now, executable_at });
+        id
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<NetworkProposal, TimelockError> {
+        self.proposals.remove(&id).ok_or(TimelockError::NotFound)
+    }
+
+    /// Removes and returns the proposal if its delay has passed.
+    pub fn take_executable(&mut self, id: u64, now: u64) -> Result<NetworkProposal, TimelockError> {
+        let proposal = self.proposals.get(&id).ok_or(TimelockError::NotFound)?;
+        if now < proposal.executable_at {
+            return Err(TimelockError::NotReady { executable_at: proposal.executable_at });
+        }
+        self.cancel(id)
+    }
+}
+
+// Public API
+
+/// Queues a network configuration change and returns the id of the proposal.
+pub fn propose_network_config(chain_id: u32, change: NetworkMut) -> Result<u64, HarmonizeError> {
+    require_role(Role::Operator);
+    state::check_get_logs_topics(change.get_logs_topics.as_ref());
+    let (caller, now) = (ic_cdk::caller(), ic_cdk::api::time());
+    let id = mutate_state(|s| -> Result<u64, HarmonizeError> {
+        if !s.networks.contains_key(&chain_id) && change.clone().into_init().is_none() {
+            return Err(TimelockError::IncompleteNetwork.into());
+        }
+        Ok(s.timelock.propose(chain_id, change.clone(), caller, now))
+    })?;
+    audit::record("propose_network_config", (chain_id, change, id));
+    Ok(id)
+}
+
+/// Cancels a pending proposal. Pausers can cancel too, so that a suspicious change can be stopped quickly.
+pub fn cancel_network_config(id: u64) -> Result<(), HarmonizeError> {
+    require_any_role(&[Role::Admin, Role::Operator, Role::Pauser]);
+    let proposal = mutate_state(|s| s.timelock.cancel(id))?;
+    audit::record("cancel_network_config", proposal);
+    Ok(())
+}
+
+pub fn execute_network_config(id: u64) -> Result<(), HarmonizeError> {
+    require_role(Role::Operator);
+    let now = ic_cdk::api::time();
+    let (proposal, old_network, new_network) = mutate_state(|s| -> Result<_, HarmonizeError> {
+        let proposal = s.timelock.take_executable(id, now)?;
+        let old_network = s.networks.get(&proposal.chain_id).map(Network::config);
+        s.apply_network_config(proposal.chain_id, proposal.change.clone());
+        let new_network = s.networks.get(&proposal.chain_id).map(Network::config);
+        Ok((proposal, old_network, new_network))
+    })?;
+    let chain_id = proposal.chain_id;
+    audit::record_change("execute_network_config", proposal, old_network, new_network);
+    chain_fusion::start_scraping(chain_id);
+    Ok(())
+}
+
+/// Sets the delay of the proposals made from now on. Reductions only take effect
+/// once the current delay has passed.
+pub fn set_network_config_delay(delay_nanos: u64) {
+    require_role(Role::Admin);
+    let now = ic_cdk::api::time();
+    let (old_delay, new_delay) = mutate_state(|s| {
+        let old_delay = (s.timelock.delay_nanos, s.timelock.pending_delay);
+        s.timelock.set_delay(delay_nanos, now);
+        (old_delay, (s.timelock.delay_nanos, s.timelock.pending_delay))
+    });
+    audit::record_change("set_network_config_delay", (delay_nanos,), old_delay, new_delay);
+}
+
+pub fn get_network_proposals() -> Vec<NetworkProposal> {
+    read_state(|s| s.timelock.proposals.values().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proposals_wait_for_the_delay() {
+        let mut timelock = Timelock::new();
+        timelock.delay_nanos = 100;
+        let id = timelock.propose(1, NetworkMut::new(), Principal::anonymous(), 1_000);
+
+        assert!(matches!(
+            timelock.take_executable(id, 1_099),
+            Err(TimelockError::NotReady { executable_at: 1_100 })
+        ));
+        assert_eq!(timelock.take_executable(id, 1_100).unwrap().chain_id, 1);
+        assert!(matches!(timelock.take_executable(id, 1_100), Err(TimelockError::NotFound)));
+
+        let id = timelock.propose(1, NetworkMut::new(), Principal::anonymous(), 2_000);
+        timelock.cancel(id).unwrap();
+        assert!(timelock.proposals.is_empty());
+    }
+
+    #[test]
+    fn test_delay_reductions_wait_for_the_current_delay() {
+        let mut timelock = Timelock::new();
+        timelock.set_delay(100, 1_000);
+        assert_eq!(timelock.delay_at(1_000), 100);
+
+        timelock.set_delay(0, 2_000);
+        assert_eq!(timelock.pending_delay, Some(PendingDelay { delay_nanos: 0, effective_at: 2_100 }));
+        assert_eq!(timelock.delay_at(2_099), 100);
+        assert_eq!(timelock.propose(1, NetworkMut::new(), Principal::anonymous(), 2_050), 0);
+        assert_eq!(timelock.proposals[&0].executable_at, 2_150);
+        assert_eq!(timelock.delay_at(2_100), 0);
+
+        // Raising the delay drops a pending reduction
+        timelock.set_delay(50, 2_050);
+        timelock.set_delay(200, 2_060);
+        assert_eq!(timelock.pending_delay, None);
+        assert_eq!(timelock.delay_at(3_000), 200);
+    }
+}
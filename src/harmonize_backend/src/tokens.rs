@@ -0,0 +1,220 @@
+//! The registry of ERC-20 tokens accepted on each network.
+//!
+//! Deposits of tokens that are not listed are held until the owner lists the
+//! token, at which point they are credited to their recipients.
+
+use std::collections::BTreeMap;
+use candid::{CandidType, Deserialize};
+use ethers_core::abi::{parse_abi, Token};
+use ethers_core::types::{H160, U256};
+use ic_cdk::println;
+use thiserror::Error;
+
+use crate::{
+    access_control::{require_role, Role},
+    audit,
+    chain_fusion::{
+        eth_call::{eth_call, EthCallError},
+        LogSource,
+    },
+    fees::{fee_entries, Operation},
+    history::TransactionKind,
+    pause::{self, PausableOperation},
+    state::{mutate_state, read_state, State},
+    types::{H160t, U256t},
+    wallet::{Account, AssetId},
+    HarmonizeError,
+};
+
+#[derive(Error, Debug, CandidType)]
+pub enum TokenError {
+    #[error("The token is not listed")]
+    NotListed,
+    #[error("The token is already listed")]
+    AlreadyListed,
+    #[error("Failed to fetch the token metadata: {0}")]
+    EthCallError(#[from] EthCallError),
+    #[error("The token returned invalid metadata")]
+    InvalidMetadata,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TokenInfo {
+    pub token: H160t,
+    pub metadata: TokenMetadata,
+}
+
+/// A deposit of a token that was not listed when the deposit was observed.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct HeldDeposit {
+    pub recipient: Account,
+    pub chain_id: u32,
+    pub token: H160t,
+    pub amount: U256t,
+    pub source: LogSource,
+}
+
+#[derive(Default)]
+pub struct Tokens {
+    /// Listed tokens keyed by `(chain_id, token)`.
+    pub tokens: BTreeMap<(u32, H160), TokenMetadata>,
+    pub held_deposits: Vec<HeldDeposit>,
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, chain_id: u32, token: H160) -> Option<&TokenMetadata> {
+        self.tokens.get(&(chain_id, token))
+    }
+
+    pub fn is_listed(&self, chain_id: u32, token: H160) -> bool {
+        self.tokens.contains_key(&(chain_id, token))
+    }
+
+    pub fn list(&mut self, chain_id: u32, token: H160, metadata: TokenMetadata) -> Result<(), TokenError> {
+        if self.is_listed(chain_id, token) {
+            return Err(TokenError::AlreadyListed);
+        }
+        self.tokens.insert((chain_id, token), metadata);
+        Ok(())
+    }
+
+    pub fn delist(&mut self, chain_id: u32, token: H160) -> Result<TokenMetadata, TokenError> {
+        self.tokens.remove(&(chain_id, token)).ok_or(TokenError::NotListed)
+    }
+
+    pub fn hold(&mut self, deposit: HeldDeposit) {
+        self.held_deposits.push(deposit);
+    }
+
+    /// Removes and returns the held deposits of `token`.
+    pub fn take_held_deposits(&mut self, chain_id: u32, token: H160) -> Vec<HeldDeposit> {
+        let (taken, kept) = std::mem::take(&mut self.held_deposits)
+            .into_iter()
+            .partition(|d| d.chain_id == chain_id && d.token.0 == token);
+        self.held_deposits = kept;
+        taken
+    }
+
+    pub fn list_network(&self, chain_id: u32) -> Vec<TokenInfo> {
+        self.tokens
+            .range((chain_id, H160::zero())..=(chain_id, H160::repeat_byte(0xff)))
+            .map(|((_, token), metadata)| TokenInfo { token: (*token).into(), metadata: metadata.clone() })
+            .collect()
+    }
+}
+
+/// Credits a deposit of a listed token, to the canonical asset it is mapped to if any.
+pub fn credit_token_deposit(s: &mut State, recipient: Account, chain_id: u32, token: H160, amount: U256, source: LogSource) {
+    let asset = s
+        .canonical_assets
+        .deposit(chain_id, token, amount)
+        .unwrap_or(AssetId::Erc20 { chain_id, token: token.into() });
+    credit_deposit(s, recipient, chain_id, asset, amount, source);
+}
+
+/// Credits a deposit of a listed asset made on `chain_id`, less the deposit fee, and records
+/// it in the history of the recipient. Deposits to frozen principals are quarantined instead.
+pub fn credit_deposit(s: &mut State, recipient: Account, chain_id: u32, asset: AssetId, amount: U256, source: LogSource) {
+    if s.compliance.is_frozen(&recipient.owner) {
+        println!("{} is frozen, quarantining the deposit", recipient);
+        s.compliance.quarantine(recipient, chain_id, asset, amount, source);
+        return;
+    }
+    let fee = s.fees.quote(Operation::Deposit, &asset, amount).min(amount);
+    if let Err(e) = s.wallets.credit_asset(recipient, &asset, amount - fee) {
+        println!("Error crediting wallet: {:?}", e);
+        return;
+    }
+    let treasury = s.treasury_account();
+    if let Err(e) = s.wallets.credit_asset(treasury, &asset, fee) {
+        println!("Error crediting the deposit fee: {:?}", e);
+    }
+    s.record_transaction(
+        std::iter::once((recipient, TransactionKind::Deposit { asset, amount: amount.into(), source }))
+            .chain(fee_entries(recipient, treasury, Operation::Deposit, asset, fee)),
+    );
+}
+
+/// Fetches `name()`, `symbol()` and `decimals()` of an ERC-20 token.
+pub async fn fetch_metadata(chain_id: u32, token: H160) -> Result<TokenMetadata, TokenError> {
+    let abi = parse_abi(&[
+        "function name() external view returns (string)",
+        "function symbol() external view returns (string)",
+        "function decimals() external view returns (uint8)",
+    ])
+    .expect("BUG: invalid ERC-20 ABI");
+    let call = |name: &'static str| {
+        let function = abi.function(name).expect("BUG: missing ERC-20 function").clone();
+        async move { eth_call(chain_id, token, &function, &[]).await }
+    };
+
+    let name = match call("name")?.as_slice() {
+        [Token::String(name)] => name.clone(),
+        _ => return Err(TokenError::InvalidMetadata),
+    };
+    let symbol = match call("symbol")?.as_slice() {
+        [Token::String(symbol)] => symbol.clone(),
+        _ => return Err(TokenError::InvalidMetadata),
+    };
+    let decimals = match call("decimals")?.as_slice() {
+        [Token::Uint(decimals)] if *decimals <= U256::from(u8::MAX) => decimals.as_u32() as u8,
+        _ => return Err(TokenError::InvalidMetadata),
+    };
+    Ok(TokenMetadata { name, symbol, decimals })
+}
+
+// Public API
+
+/// Lists a token and releases the deposits held for it.
+pub async fn add_token(chain_id: u32, token: H160) -> Result<TokenMetadata, HarmonizeError> {
+    require_role(Role::Operator);
+    if read_state(|s| s.tokens.is_listed(chain_id, token)) {
+        return Err(TokenError::AlreadyListed.into());
+    }
+    // Listing releases the held deposits of the token
+    pause::check(PausableOperation::DepositCrediting, Some(chain_id))?;
+    let metadata = fetch_metadata(chain_id, token).await?;
+    mutate_state(|s| -> Result<(), HarmonizeError> {
+        s.tokens.list(chain_id, token, metadata.clone())?;
+        for deposit in s.tokens.take_held_deposits(chain_id, token) {
+            credit_token_deposit(s, deposit.recipient, chain_id, token, deposit.amount.into(), deposit.source);
+        }
+        Ok(())
+    })?;
+    audit::record("add_token", (chain_id, token));
+    Ok(metadata)
+}
+
+/// Delists a token. Existing balances are kept, new deposits are held.
+pub fn remove_token(chain_id: u32, token: H160) -> Result<(), HarmonizeError> {
+    require_role(Role::Operator);
+    mutate_state(|s| s.tokens.delist(chain_id, token))?;
+    audit::record("remove_token", (chain_id, token));
+    Ok(())
+}
+
+pub fn get_tokens(chain_id: u32) -> Vec<TokenInfo> {
+    read_state(|s| s.tokens.list_network(chain_id))
+}
+
+pub fn get_held_deposits(chain_id: u32) -> Vec<HeldDeposit> {
+    read_state(|s| {
+        s.tokens
+            .held_deposits
+            .iter()
+            .filter(|d| d.chain_id == chain_id)
+            .cloned()
+            .collect()
+    })
+}
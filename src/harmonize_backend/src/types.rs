@@ -4,7 +4,7 @@ use serde::{Serialize, Deserialize};
 use std::{rc::Rc, str::FromStr};
 
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct H160t (pub H160);
 
 impl From<H160t> for H160 {
@@ -62,7 +62,7 @@ impl std::fmt::Display for H160t {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
 pub struct U256t (pub U256);
 
 impl From<U256t> for U256 {
@@ -140,8 +140,21 @@ impl H256Ext for U256 {
     }
 }
 
+pub trait U256Ext {
+    fn to_nat(&self) -> Nat;
+}
+
+impl U256Ext for U256 {
+    fn to_nat(&self) -> Nat {
+        let mut be_bytes = [0u8; 32];
+        self.to_big_endian(&mut be_bytes);
+        Nat::from(num::BigUint::from_bytes_be(&be_bytes))
+    }
+}
+
 pub trait NatExt {
     fn to_u256(&self) -> U256;
+    fn try_to_u256(&self) -> Option<U256>;
 }
 
 impl NatExt for Nat {
@@ -149,4 +162,12 @@ impl NatExt for Nat {
         let be_bytes = self.0.to_bytes_be();
         U256::from_big_endian(&be_bytes)
     }
+
+    fn try_to_u256(&self) -> Option<U256> {
+        let be_bytes = self.0.to_bytes_be();
+        if be_bytes.len() > 32 {
+            return None;
+        }
+        Some(U256::from_big_endian(&be_bytes))
+    }
 }
\ No newline at end of file
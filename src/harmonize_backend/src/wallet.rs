@@ -1,18 +1,27 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
-use candid::{CandidType, Principal};
+use std::{collections::{BTreeMap, HashMap}, fmt::Display, future::Future, hash::Hash};
+use candid::{CandidType, Deserialize, Principal};
 use ethers_core::types::{H160, U256};
+use ic_cdk::println;
 use thiserror::Error;
-use typemap::TypeMap;
-use unsafe_any::UnsafeAny;
-use crate::{chain_fusion::job::safe, read_state, state::mutate_state, HarmonizeError};
+use crate::{
+    chain_fusion::{evm_rpc::TransactionReceipt, job::safe::{self, TransactionError}},
+    compliance,
+    fees::{self, Operation},
+    pause::{self, PausableOperation},
+    history::{self, TransactionKind},
+    icrc1::NATIVE_DECIMALS,
+    read_state,
+    settlement::WithdrawalHold,
+    state::{mutate_state, State},
+    types::{H160t, U256t},
+    HarmonizeError,
+};
 
 pub mod balances {
     use std::{collections::BTreeMap, fmt::{Debug, Display}, ops::{Sub, SubAssign}};
     use candid::CandidType;
-    use ethers_core::types::{H160, U256};
+    use ethers_core::types::U256;
     use thiserror::Error;
-    
-    
 
     pub trait CheckedAdd: Sized {
         fn checked_add(self, other: Self) -> Option<Self>;
@@ -34,7 +43,7 @@ pub mod balances {
         }
     }
 
-    #[derive(Error, Debug, CandidType)]
+    #[derive(Error, Debug, Clone, Copy, CandidType)]
     pub enum BalanceError {
         #[error("Arithmetic overflow")]
         ArithmeticOverflow,
@@ -42,6 +51,8 @@ pub mod balances {
         InsufficientBalance,
         #[error("Not found")]
         NotFound,
+        #[error("Insufficient held balance")]
+        InsufficientHeld,
     }
 
     fn safe_transfer<Value>(from: &mut Value, to: &mut Value, amount: Value) -> Result<(), BalanceError>
@@ -69,6 +80,14 @@ pub mod balances {
         fn get(&self, key: &Self::Key) -> Option<&Self::Value>;
         fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value>;
 
+        /// Moves `amount` from the available to the held balance, so that it can no longer be spent.
+        fn hold(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError>;
+        /// Removes `amount` from the held balance for good.
+        fn capture(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError>;
+        /// Moves `amount` from the held balance back to the available balance.
+        fn release(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError>;
+        fn held(&self, key: &Self::Key) -> Option<&Self::Value>;
+
         fn transfer(&mut self, to: &mut Self, key: &Self::Key, amount: Self::Value) -> Result<(), BalanceError> {
             assert!(amount >= Self::Value::zero(), "Amount must be positive");
             let from = self.get_mut(key).ok_or(BalanceError::NotFound)?;
@@ -77,15 +96,56 @@ pub mod balances {
         }
     }
 
-    pub struct Balances<Key, Value> (BTreeMap<Key, Value>);
+    /// Available and held balances. Keys with a zero balance are not stored.
+    pub struct Balances<Key, Value> {
+        available: BTreeMap<Key, Value>,
+        held: BTreeMap<Key, Value>,
+    }
 
     impl<Key, Value> Balances<Key, Value> {
         pub fn new() -> Self {
-            Balances(BTreeMap::new())
+            Balances {
+                available: BTreeMap::new(),
+                held: BTreeMap::new(),
+            }
+        }
+        pub fn iter(&self) -> impl Iterator<Item=(&Key, &Value)> {
+            self.available.iter()
+        }
+        pub fn iter_held(&self) -> impl Iterator<Item=(&Key, &Value)> {
+            self.held.iter()
+        }
+    }
+
+    fn checked_debit<Key, Value>(balances: &mut BTreeMap<Key, Value>, key: &Key, amount: Value, error: BalanceError) -> Result<Value, BalanceError>
+    where
+        Key: Ord,
+        Value: Clone + Ord + SubAssign + Zero
+    {
+        if amount == Zero::zero() {
+            return Ok(balances.get(key).cloned().unwrap_or_else(Zero::zero));
         }
-        fn keys(&self) -> impl Iterator<Item=&Key> {
-            self.0.keys()
+        let balance = balances.get_mut(key).ok_or(error)?;
+        if *balance < amount {
+            return Err(error);
         }
+        *balance -= amount;
+        let balance = balance.clone();
+        // Remove the balance if it is zero
+        if balance == Zero::zero() {
+            balances.remove(key);
+        }
+        Ok(balance)
+    }
+
+    fn checked_credit<Key, Value>(balances: &mut BTreeMap<Key, Value>, key: &Key, amount: Value) -> Result<Value, BalanceError>
+    where
+        Key: Clone + Ord,
+        Value: Clone + CheckedAdd + Zero
+    {
+        let balance = balances.entry(key.clone()).or_insert_with(Zero::zero);
+        *balance = balance.clone().checked_add(amount).ok_or(BalanceError::ArithmeticOverflow)?;
+        Ok(balance.clone())
     }
 
     impl<Key, Value> BalanceStore for Balances<Key, Value>
@@ -98,119 +158,57 @@ pub mod balances {
 
         fn credit(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
             assert!(amount >= Value::zero(), "Amount must be positive");
-
-            let balance = self.0.entry(key.clone()).or_insert_with(Zero::zero);
-            *balance = balance.clone().checked_add(amount).ok_or(BalanceError::ArithmeticOverflow)?;
-            Ok(balance.clone())
+            checked_credit(&mut self.available, key, amount)
         }
 
         fn debit(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
             assert!(amount >= Zero::zero(), "Amount must be positive");
-
-            let balance = self.0
-                .get_mut(key)
-                .ok_or(BalanceError::InsufficientBalance)
-                .and_then(|balance| {
-                    if *balance >= amount {
-                        *balance -= amount;
-                        Ok(balance.clone())
-                    } else {
-                        Err(BalanceError::InsufficientBalance)
-                    }
-                });
-
-            // Remove the balance if it is zero
-            match &balance {
-                Ok(b) if b == &Zero::zero() => {
-                    self.0.remove(key);
-                }
-                _ => {}
-            }
-            balance
+            checked_debit(&mut self.available, key, amount, BalanceError::InsufficientBalance)
         }
 
         fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
-            self.0.get(key)
+            self.available.get(key)
         }
 
         fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
-            self.0.get_mut(key)
+            self.available.get_mut(key)
         }
         
         fn get_or_create_mut(&mut self, key: &Self::Key) -> &mut Self::Value {
-            self.0.entry(key.clone()).or_insert_with(Zero::zero)
+            self.available.entry(key.clone()).or_insert_with(Zero::zero)
         }
-    }
 
-    impl<Key, Value> Default for Balances<Key, Value> {
-        fn default() -> Self {
-            Balances::new()
+        fn hold(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
+            assert!(amount >= Zero::zero(), "Amount must be positive");
+            if self.held.get(key).is_some_and(|held| held.clone().checked_add(amount.clone()).is_none()) {
+                return Err(BalanceError::ArithmeticOverflow);
+            }
+            checked_debit(&mut self.available, key, amount.clone(), BalanceError::InsufficientBalance)?;
+            checked_credit(&mut self.held, key, amount)
         }
-    }
-
-    pub struct GroupedBalances<Group, Key, Value> (BTreeMap<Group, Balances<Key, Value>>);
 
-    impl<Group, Key, Value> GroupedBalances<Group, Key, Value>
-    where 
-        Group: Ord
-    {
-        pub fn new() -> Self {
-            GroupedBalances(BTreeMap::new())
-        }
-        pub fn groups(&self) -> impl Iterator<Item=&Group> {
-            self.0.keys()
-        }
-        pub fn group_keys(&self, group: &Group) -> Option<impl Iterator<Item=&Key>> {
-            self.0.get(group).map(|balances| balances.keys())
+        fn capture(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
+            assert!(amount >= Zero::zero(), "Amount must be positive");
+            checked_debit(&mut self.held, key, amount, BalanceError::InsufficientHeld)
         }
-    }
 
-    impl<Group, Key, Value> BalanceStore for GroupedBalances<Group, Key, Value>
-    where 
-        Group: Clone + Display + Ord,
-        Key: Clone + Display + Ord,
-        Value: Clone + CheckedAdd + Ord + SubAssign + Zero + Sub<Output=Value>
-    {
-        type Key = (Group, Key);
-        type Value = Value;
-        
-        fn credit(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
-            let (group, key) = key;
-            self.0.entry(group.clone()).or_default().credit(key, amount)
-        }
-        
-        fn debit(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
-            let (group, key) = key;
-            match self.0.get_mut(group).ok_or(BalanceError::NotFound)?.debit(key, amount) {
-                Ok(balance) if balance == Zero::zero() => {
-                    if self.0.get(group).map(|balances| balances.0.is_empty()).unwrap_or(false) {
-                        self.0.remove(group);
-                    }
-                    Ok(balance)
-                },
-                result => result
+        fn release(&mut self, key: &Self::Key, amount: Self::Value) -> Result<Self::Value, BalanceError> {
+            assert!(amount >= Zero::zero(), "Amount must be positive");
+            if self.available.get(key).is_some_and(|available| available.clone().checked_add(amount.clone()).is_none()) {
+                return Err(BalanceError::ArithmeticOverflow);
             }
-        }
-        
-        fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
-            let (group, key) = key;
-            self.0.get(group)?.get(key)
-        }
-        
-        fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
-            let (group, key) = key;
-            self.0.get_mut(group)?.get_mut(key)
+            checked_debit(&mut self.held, key, amount.clone(), BalanceError::InsufficientHeld)?;
+            checked_credit(&mut self.available, key, amount)
         }
 
-        fn get_or_create_mut(&mut self, key: &Self::Key) -> &mut Self::Value {
-            let (group, key) = key;
-            self.0.entry(group.clone()).or_default().get_or_create_mut(key)
+        fn held(&self, key: &Self::Key) -> Option<&Self::Value> {
+            self.held.get(key)
         }
     }
 
-    impl Default for GroupedBalances<u32, H160, U256> {
+    impl<Key, Value> Default for Balances<Key, Value> {
         fn default() -> Self {
-            GroupedBalances::new()
+            Balances::new()
         }
     }
 }
@@ -220,12 +218,185 @@ pub enum WalletError {
     #[error(transparent)]
     BalanceError(#[from] BalanceError),
     #[error("Wallet not found")]
-    NotFound
+    NotFound,
+    #[error("Insufficient allowance")]
+    InsufficientAllowance,
+    #[error("Allowance expired")]
+    AllowanceExpired,
+    #[error("Cannot approve spending to oneself")]
+    SelfApproval,
+}
+
+/// A leg of a batch transfer that was rejected, with its index in the batch.
+#[derive(Debug, CandidType)]
+pub struct RejectedLeg {
+    pub index: u64,
+    pub error: WalletError,
+}
+
+#[derive(Error, Debug, CandidType)]
+#[error("{} leg(s) of the batch were rejected", .rejected.len())]
+pub struct BatchTransferError {
+    pub rejected: Vec<RejectedLeg>,
+}
+
+use balances::*;
+
+pub type Subaccount = [u8; 32];
+
+pub const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+/// A wallet owned by a principal. A principal holds a separate wallet for every subaccount.
+#[derive(CandidType, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// Creates an account, treating the all-zero subaccount as the default one.
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Account {
+            owner,
+            subaccount: subaccount.filter(|subaccount| *subaccount != DEFAULT_SUBACCOUNT),
+        }
+    }
+
+    /// Returns the same account with the default subaccount normalized to `None`.
+    pub fn normalized(self) -> Self {
+        Account::new(self.owner, self.subaccount)
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.subaccount {
+            Some(subaccount) => write!(f, "{}.{}", self.owner, hex::encode(subaccount)),
+            None => write!(f, "{}", self.owner),
+        }
+    }
+}
+
+/// Identifies an asset that can be held in a wallet.
+#[derive(CandidType, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AssetId {
+    /// The native currency of a network.
+    Native { chain_id: u32 },
+    /// An ERC-20 token deployed on a network.
+    Erc20 { chain_id: u32, token: H160t },
+    /// A token unified across networks, see [`crate::canonical`].
+    Canonical { id: u32 },
+}
+
+impl AssetId {
+    /// The network of the asset, or `None` for canonical assets which span several networks.
+    pub fn chain_id(&self) -> Option<u32> {
+        match self {
+            AssetId::Native { chain_id } => Some(*chain_id),
+            AssetId::Erc20 { chain_id, .. } => Some(*chain_id),
+            AssetId::Canonical { .. } => None,
+        }
+    }
+}
+
+impl Display for AssetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetId::Native { chain_id } => write!(f, "native/{}", chain_id),
+            AssetId::Erc20 { chain_id, token } => write!(f, "erc20/{}/{}", chain_id, token),
+            AssetId::Canonical { id } => write!(f, "canonical/{}", id),
+        }
+    }
+}
+
+/// A kind of asset, mapping a kind specific key to an [`AssetId`].
+pub trait AssetKind {
+    type Key;
+
+    fn asset_id(key: &Self::Key) -> AssetId;
+}
+
+pub struct Eth;
+pub struct Erc20;
+
+impl AssetKind for Eth {
+    type Key = u32;
+
+    fn asset_id(chain_id: &Self::Key) -> AssetId {
+        AssetId::Native { chain_id: *chain_id }
+    }
+}
+
+impl AssetKind for Erc20 {
+    type Key = (u32, H160);
+
+    fn asset_id((chain_id, token): &Self::Key) -> AssetId {
+        AssetId::Erc20 { chain_id: *chain_id, token: (*token).into() }
+    }
+}
+
+/// An amount of an asset that a spender may move out of an owner's wallet.
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct Allowance {
+    pub amount: U256t,
+    /// Nanoseconds since the UNIX epoch after which the allowance can no longer be used.
+    pub expires_at: Option<u64>,
+}
+
+impl Allowance {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A single transfer out of the caller's wallet within a batch.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TransferLeg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub asset: AssetId,
+    pub amount: U256t,
+}
+
+/// The balance of an asset in a wallet.
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct Balance {
+    /// The amount that can be spent.
+    pub available: U256t,
+    /// The amount reserved by operations in flight, such as withdrawals.
+    pub held: U256t,
+}
+
+/// The balance of a single ERC-20 token.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TokenBalance {
+    pub token: H160t,
+    pub amount: U256t,
+    /// The symbol and decimals of the token, if it is listed.
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+}
+
+/// All non-zero balances of a wallet on one network.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ChainBalances {
+    pub chain_id: u32,
+    pub native: U256t,
+    pub native_decimals: u8,
+    pub erc20: Vec<TokenBalance>,
 }
 
 #[derive(Default)]
 pub struct Wallets<Id> {
     pub wallets: HashMap<Id, Wallet<Id>>,
+    /// Allowances keyed by `(owner, spender, asset)`.
+    pub allowances: HashMap<(Id, Id, AssetId), Allowance>,
 }
 
 impl<Id> Wallets<Id>
@@ -235,6 +406,7 @@ where
     pub fn new() -> Self {
         Wallets {
             wallets: HashMap::default(),
+            allowances: HashMap::default(),
         }
     }
     pub fn exists(&self, id: Id) -> bool {
@@ -257,214 +429,607 @@ where
         self.wallets.insert(wallet.id.clone(), wallet);
     }
 
-    pub fn transfer<K>(
-        &mut self,
-        from: Id,
-        to: Id,
-        key: &<K::Value as BalanceStore>::Key,
-        amount: <K::Value as BalanceStore>::Value
-    ) -> Result<(), WalletError>
-    where 
-        K: Key,
-        K::Value: BalanceStore + Default
-    {
-        self.debit::<K>(from.clone(), key, amount.clone())?;
-        if let Err(e) = self.credit::<K>(to, key, amount.clone()) {
-            self.credit::<K>(from, key, amount).expect("BUG: Failed to rollback");
+    pub fn create_default(&mut self, wallet: Id) {
+        self.wallets.entry(wallet.clone()).or_insert(Wallet::new(wallet));
+    }
+
+    pub fn transfer<K: AssetKind>(&mut self, from: Id, to: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.transfer_asset(from, to, &K::asset_id(key), amount)
+    }
+
+    pub fn credit<K: AssetKind>(&mut self, wallet: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.credit_asset(wallet, &K::asset_id(key), amount)
+    }
+
+    pub fn debit<K: AssetKind>(&mut self, wallet: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.debit_asset(wallet, &K::asset_id(key), amount)
+    }
+
+    pub fn get_balance<K: AssetKind>(&self, wallet: Id, key: &K::Key) -> Option<&U256> {
+        self.get_asset_balance(wallet, &K::asset_id(key))
+    }
+
+    pub fn get_balance_or_default<K: AssetKind>(&self, wallet: Id, key: &K::Key) -> U256 {
+        self.get_balance::<K>(wallet, key).cloned().unwrap_or_else(Zero::zero)
+    }
+
+    pub fn hold<K: AssetKind>(&mut self, wallet: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.hold_asset(wallet, &K::asset_id(key), amount)
+    }
+
+    pub fn capture<K: AssetKind>(&mut self, wallet: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.capture_asset(wallet, &K::asset_id(key), amount)
+    }
+
+    pub fn release<K: AssetKind>(&mut self, wallet: Id, key: &K::Key, amount: U256) -> Result<(), WalletError> {
+        self.release_asset(wallet, &K::asset_id(key), amount)
+    }
+
+    pub fn transfer_asset(&mut self, from: Id, to: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.debit_asset(from.clone(), asset, amount)?;
+        if let Err(e) = self.credit_asset(to, asset, amount) {
+            self.credit_asset(from, asset, amount).expect("BUG: Failed to rollback");
             return Err(e);
         }
         Ok(())
     }
 
-    pub fn credit<K>(
-        &mut self,
-        wallet: Id,
-        key: &<K::Value as BalanceStore>::Key,
-        amount: <K::Value as BalanceStore>::Value
-    ) -> Result<(), WalletError>
-    where 
-        K: Key,
-        K::Value: BalanceStore + Default
-    {
-        self.get_or_create_mut(wallet).credit::<K>(key, amount)?;
+    /// Applies a batch of `(from, to, asset, amount)` transfers in order, either all of them or none.
+    ///
+    /// Every leg is checked against the balances left by the legs before it, and the
+    /// errors of all rejected legs are returned together with their index.
+    pub fn batch_transfer(&mut self, legs: &[(Id, Id, AssetId, U256)]) -> Result<(), Vec<(usize, WalletError)>> {
+        let mut balances = HashMap::<(Id, AssetId), Option<U256>>::new();
+        let mut rejected = vec![];
+        for (index, (from, to, asset, amount)) in legs.iter().enumerate() {
+            let from_balance = *balances
+                .entry((from.clone(), *asset))
+                .or_insert_with(|| self.get_asset_balance(from.clone(), asset).cloned());
+            let remaining = match from_balance {
+                Some(balance) if balance >= *amount => balance - *amount,
+                _ => {
+                    rejected.push((index, BalanceError::InsufficientBalance.into()));
+                    continue;
+                }
+            };
+            balances.insert((from.clone(), *asset), (!remaining.is_zero()).then_some(remaining));
+            let to_balance = *balances
+                .entry((to.clone(), *asset))
+                .or_insert_with(|| self.get_asset_balance(to.clone(), asset).cloned());
+            match to_balance.unwrap_or_default().checked_add(*amount) {
+                Some(balance) => {
+                    balances.insert((to.clone(), *asset), Some(balance));
+                }
+                None => {
+                    balances.insert((from.clone(), *asset), from_balance);
+                    rejected.push((index, BalanceError::ArithmeticOverflow.into()));
+                }
+            }
+        }
+        if !rejected.is_empty() {
+            return Err(rejected);
+        }
+        for (from, to, asset, amount) in legs {
+            self.transfer_asset(from.clone(), to.clone(), asset, *amount)
+                .expect("BUG: a checked batch leg failed");
+        }
         Ok(())
     }
 
-    pub fn create_default(&mut self, wallet: Id) {
-        self.wallets.entry(wallet.clone()).or_insert(Wallet::new(wallet));
+    pub fn credit_asset(&mut self, wallet: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.get_or_create_mut(wallet).credit(asset, amount)?;
+        Ok(())
     }
 
-    pub fn debit<K>(
-        &mut self,
-        wallet: Id,
-        key: &<K::Value as BalanceStore>::Key,
-        amount: <K::Value as BalanceStore>::Value
-    ) -> Result<(), WalletError>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.get_mut(wallet).ok_or(WalletError::NotFound)?.debit::<K>(key, amount)?;
+    pub fn debit_asset(&mut self, wallet: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.get_mut(wallet).ok_or(WalletError::NotFound)?.debit(asset, amount)?;
         Ok(())
     }
 
-    pub fn get_balance<K>(
-        &self,
-        wallet: Id,
-        key: &<K::Value as BalanceStore>::Key,
-    ) -> Option<&<K::Value as BalanceStore>::Value>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.get(wallet)?.get_balance::<K>(key)
+    pub fn get_asset_balance(&self, wallet: Id, asset: &AssetId) -> Option<&U256> {
+        self.get(wallet)?.get_balance(asset)
     }
 
-    pub fn get_balance_or_default<K>(
-        &self,
-        wallet: Id,
-        key: &<K::Value as BalanceStore>::Key,
-    ) -> <K::Value as BalanceStore>::Value
-    where 
-        K: Key,
-        K::Value: BalanceStore,
-        <K::Value as BalanceStore>::Value: Zero
-    {
-        self.get(wallet).and_then(|wallet| {
-            wallet.get_balance::<K>(key)
-        }).cloned().unwrap_or_else(Zero::zero)
+    pub fn hold_asset(&mut self, wallet: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.get_mut(wallet).ok_or(WalletError::NotFound)?.hold(asset, amount)?;
+        Ok(())
     }
-}
 
-use balances::*;
+    pub fn capture_asset(&mut self, wallet: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.get_mut(wallet).ok_or(WalletError::NotFound)?.capture(asset, amount)?;
+        Ok(())
+    }
 
-pub struct Eth;
-pub struct Erc20;
+    pub fn release_asset(&mut self, wallet: Id, asset: &AssetId, amount: U256) -> Result<(), WalletError> {
+        self.get_mut(wallet).ok_or(WalletError::NotFound)?.release(asset, amount)?;
+        Ok(())
+    }
+
+    pub fn get_asset_held(&self, wallet: Id, asset: &AssetId) -> Option<&U256> {
+        self.get(wallet)?.get_held(asset)
+    }
+
+    /// Sums the available balances of `asset` over all wallets.
+    pub fn total_available(&self, asset: &AssetId) -> U256 {
+        self.wallets
+            .values()
+            .filter_map(|wallet| wallet.get_balance(asset))
+            .fold(U256::zero(), |total, balance| total.saturating_add(*balance))
+    }
+
+    /// Sums the held balances of `asset` over all wallets.
+    pub fn total_held(&self, asset: &AssetId) -> U256 {
+        self.wallets
+            .values()
+            .filter_map(|wallet| wallet.get_held(asset))
+            .fold(U256::zero(), |total, held| total.saturating_add(*held))
+    }
+
+    /// Sums the available and held balances of `asset` over all wallets.
+    pub fn total_balance(&self, asset: &AssetId) -> U256 {
+        self.wallets
+            .values()
+            .flat_map(|wallet| wallet.get_balance(asset).into_iter().chain(wallet.get_held(asset)))
+            .fold(U256::zero(), |total, balance| total.saturating_add(*balance))
+    }
 
-use typemap::Key;
+    /// Sets the amount of `asset` that `spender` may transfer out of the `owner`'s wallet,
+    /// replacing any previous allowance.
+    pub fn approve(
+        &mut self,
+        owner: Id,
+        spender: Id,
+        asset: &AssetId,
+        amount: U256,
+        expires_at: Option<u64>,
+        now: u64
+    ) -> Result<(), WalletError> {
+        if owner == spender {
+            return Err(WalletError::SelfApproval);
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(WalletError::AllowanceExpired);
+        }
+        let key = (owner, spender, *asset);
+        if amount.is_zero() {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(key, Allowance { amount: amount.into(), expires_at });
+        }
+        Ok(())
+    }
+
+    pub fn allowance(&self, owner: Id, spender: Id, asset: &AssetId, now: u64) -> Allowance {
+        self.allowances
+            .get(&(owner, spender, *asset))
+            .filter(|allowance| !allowance.is_expired(now))
+            .cloned()
+            .unwrap_or_default()
+    }
 
-impl Key for Eth {
-    type Value = Balances<u32, U256>;
+    /// Transfers `amount` of `asset` from `from` to `to` on behalf of `spender`,
+    /// using up the allowance granted by `from`.
+    ///
+    /// The optional `(collector, fee)` is paid out of `from` as well and must be
+    /// covered by the allowance.
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer_from(
+        &mut self,
+        spender: Id,
+        from: Id,
+        to: Id,
+        asset: &AssetId,
+        amount: U256,
+        fee: Option<(Id, U256)>,
+        now: u64
+    ) -> Result<(), WalletError> {
+        let key = (from.clone(), spender, *asset);
+        let allowance = match self.allowances.get(&key) {
+            Some(allowance) if allowance.is_expired(now) => return Err(WalletError::AllowanceExpired),
+            Some(allowance) => allowance.amount.0,
+            None => U256::zero(),
+        };
+        let fee = fee.filter(|(_, fee)| !fee.is_zero());
+        let total = match &fee {
+            Some((_, fee)) => amount.checked_add(*fee).ok_or(BalanceError::ArithmeticOverflow)?,
+            None => amount,
+        };
+        if allowance < total {
+            return Err(WalletError::InsufficientAllowance);
+        }
+        let mut legs = vec![(from.clone(), to, *asset, amount)];
+        if let Some((collector, fee)) = fee {
+            legs.push((from, collector, *asset, fee));
+        }
+        self.batch_transfer(&legs).map_err(first_rejection)?;
+        if allowance == total {
+            self.allowances.remove(&key);
+        } else if let Some(allowance) = self.allowances.get_mut(&key) {
+            allowance.amount = (allowance.amount.0 - total).into();
+        }
+        Ok(())
+    }
 }
 
-impl Key for Erc20 {
-    type Value = GroupedBalances<u32, H160, U256>;
+/// The error of the first rejected leg of a batch.
+pub fn first_rejection(rejected: Vec<(usize, WalletError)>) -> WalletError {
+    rejected.into_iter().next().expect("BUG: a batch was rejected without errors").1
 }
 
 pub struct Wallet<Id> {
     pub id: Id,
-    pub balances: TypeMap<dyn UnsafeAny>,
+    pub balances: Balances<AssetId, U256>,
 }
 
 impl<Id> Wallet<Id> {
     pub fn new(id: Id) -> Self {
         Wallet {
             id,
-            balances: TypeMap::new(),
+            balances: Balances::new(),
         }
     }
-    pub fn credit<K>(&mut self, key: &<K::Value as BalanceStore>::Key, amount: <K::Value as BalanceStore>::Value) -> Result<<K::Value as BalanceStore>::Value, WalletError>
-    where 
-        K: Key,
-        K::Value: BalanceStore + Default
-    {
-        self.balances.entry::<K>().or_insert_with(Default::default).credit(key, amount).map_err(Into::into)
+    pub fn credit(&mut self, asset: &AssetId, amount: U256) -> Result<U256, WalletError> {
+        self.balances.credit(asset, amount).map_err(Into::into)
     }
-    pub fn debit<K>(&mut self, key: &<K::Value as BalanceStore>::Key, amount: <K::Value as BalanceStore>::Value) -> Result<<K::Value as BalanceStore>::Value, WalletError>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.balances.get_mut::<K>().ok_or(WalletError::NotFound)?.debit(key, amount).map_err(Into::into)
+    pub fn debit(&mut self, asset: &AssetId, amount: U256) -> Result<U256, WalletError> {
+        self.balances.debit(asset, amount).map_err(Into::into)
     }
-    pub fn get<K>(&self) -> Option<&K::Value>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.balances.get::<K>()
+    pub fn get_balance(&self, asset: &AssetId) -> Option<&U256> {
+        self.balances.get(asset)
     }
-    pub fn get_mut<K>(&mut self) -> Option<&mut K::Value>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.balances.get_mut::<K>()
+    pub fn get_balance_mut(&mut self, asset: &AssetId) -> Option<&mut U256> {
+        self.balances.get_mut(asset)
     }
-    pub fn get_balance<K>(&self, key: &<K::Value as BalanceStore>::Key) -> Option<&<K::Value as BalanceStore>::Value>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.balances.get::<K>().and_then(|balances| {
-            balances.get(key)
-        })
+    pub fn hold(&mut self, asset: &AssetId, amount: U256) -> Result<U256, WalletError> {
+        self.balances.hold(asset, amount).map_err(Into::into)
     }
-    pub fn get_balance_mut<K>(&mut self, key: &<K::Value as BalanceStore>::Key) -> Option<&mut <K::Value as BalanceStore>::Value>
-    where 
-        K: Key,
-        K::Value: BalanceStore
-    {
-        self.balances.get_mut::<K>().and_then(|balances| {
-            balances.get_mut(key)
-        })
+    pub fn capture(&mut self, asset: &AssetId, amount: U256) -> Result<U256, WalletError> {
+        self.balances.capture(asset, amount).map_err(Into::into)
+    }
+    pub fn release(&mut self, asset: &AssetId, amount: U256) -> Result<U256, WalletError> {
+        self.balances.release(asset, amount).map_err(Into::into)
+    }
+    pub fn get_held(&self, asset: &AssetId) -> Option<&U256> {
+        self.balances.held(asset)
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&AssetId, &U256)> {
+        self.balances.iter()
+    }
+    pub fn iter_held(&self) -> impl Iterator<Item=(&AssetId, &U256)> {
+        self.balances.iter_held()
+    }
+
+    /// Groups the non-zero balances of the wallet by network, leaving out canonical assets.
+    pub fn chain_balances(&self) -> Vec<ChainBalances> {
+        let mut chains = BTreeMap::<u32, ChainBalances>::new();
+        for (asset, amount) in self.iter().filter(|(_, amount)| !amount.is_zero()) {
+            let Some(chain_id) = asset.chain_id() else {
+                continue;
+            };
+            let chain = chains.entry(chain_id).or_insert_with(|| ChainBalances {
+                chain_id,
+                native: U256t::default(),
+                native_decimals: NATIVE_DECIMALS,
+                erc20: vec![],
+            });
+            match asset {
+                AssetId::Native { .. } => chain.native = (*amount).into(),
+                AssetId::Erc20 { token, .. } => chain.erc20.push(TokenBalance {
+                    token: *token,
+                    amount: (*amount).into(),
+                    symbol: None,
+                    decimals: None,
+                }),
+                AssetId::Canonical { .. } => unreachable!("canonical assets have no network"),
+            }
+        }
+        chains.into_values().collect()
     }
 }
 
 // Public API
 
-pub fn get_erc20_balance(wallet: Principal, network_id: u32, token: H160) -> U256 {
+pub fn get_erc20_balance(wallet: Account, network_id: u32, token: H160) -> U256 {
     read_state(|s| {
         s.wallets.get_balance_or_default::<Erc20>(wallet, &(network_id, token))
     })
 }
 
-pub fn get_eth_balance(wallet: Principal, network_id: u32) -> U256 {
+pub fn get_eth_balance(wallet: Account, network_id: u32) -> U256 {
     read_state(|s| {
         s.wallets.get_balance_or_default::<Eth>(wallet, &network_id)
     })
 }
 
-pub fn transfer_erc20(from: Principal, to: Principal, network_id: u32, token: H160, amount: U256) -> Result<(), HarmonizeError> {
-    mutate_state(|s| {
-        s.wallets.transfer::<Erc20>(from, to, &(network_id, token), amount)
-    })?;
-    Ok(())
+pub fn get_balance(wallet: Account, asset: AssetId) -> Balance {
+    read_state(|s| Balance {
+        available: s.wallets.get_asset_balance(wallet, &asset).cloned().unwrap_or_default().into(),
+        held: s.wallets.get_asset_held(wallet, &asset).cloned().unwrap_or_default().into(),
+    })
+}
+
+pub fn get_balances(wallet: Account) -> Vec<ChainBalances> {
+    read_state(|s| {
+        let mut balances = s.wallets.get(wallet).map(Wallet::chain_balances).unwrap_or_default();
+        for chain in balances.iter_mut() {
+            for balance in chain.erc20.iter_mut() {
+                if let Some(metadata) = s.tokens.get(chain.chain_id, balance.token.0) {
+                    balance.symbol = Some(metadata.symbol.clone());
+                    balance.decimals = Some(metadata.decimals);
+                }
+            }
+        }
+        balances
+    })
 }
 
-pub fn transfer_eth(from: Principal, to: Principal, network_id: u32, amount: U256) -> Result<(), HarmonizeError> {
+/// Transfers `amount` of `asset` between two wallets, plus the transfer fee to the treasury,
+/// and returns the id of the recorded transaction.
+pub fn transfer_asset(from: Account, to: Account, asset: AssetId, amount: U256) -> Result<u64, HarmonizeError> {
+    pause::check(PausableOperation::Transfers, asset.chain_id())?;
+    compliance::check_transfer(&from, &to)?;
+    mutate_state(|s| fees::transfer_with_fees(s, &[(from, to, asset, amount)]))
+        .map_err(|rejected| first_rejection(rejected).into())
+}
+
+/// Transfers all `legs` out of the caller's wallets, or none of them if any leg is rejected.
+/// Each leg pays its own transfer fee.
+///
+/// All legs share the id of the recorded transaction, which is returned.
+pub fn batch_transfer(caller: Principal, legs: Vec<TransferLeg>) -> Result<u64, HarmonizeError> {
+    let legs: Vec<_> = legs
+        .into_iter()
+        .map(|leg| (Account::new(caller, leg.from_subaccount), leg.to.normalized(), leg.asset, leg.amount.0))
+        .collect();
+    for (from, to, asset, _) in &legs {
+        pause::check(PausableOperation::Transfers, asset.chain_id())?;
+        compliance::check_transfer(from, to)?;
+    }
+    mutate_state(|s| fees::transfer_with_fees(s, &legs)).map_err(|rejected| {
+        BatchTransferError {
+            rejected: rejected
+                .into_iter()
+                .map(|(index, error)| RejectedLeg { index: index as u64, error })
+                .collect(),
+        }
+        .into()
+    })
+}
+
+pub fn transfer_erc20(from: Account, to: Account, network_id: u32, token: H160, amount: U256) -> Result<u64, HarmonizeError> {
+    transfer_asset(from, to, Erc20::asset_id(&(network_id, token)), amount)
+}
+
+pub fn transfer_eth(from: Account, to: Account, network_id: u32, amount: U256) -> Result<u64, HarmonizeError> {
+    transfer_asset(from, to, Eth::asset_id(&network_id), amount)
+}
+
+pub fn approve(owner: Account, spender: Account, asset: AssetId, amount: U256, expires_at: Option<u64>) -> Result<(), HarmonizeError> {
     mutate_state(|s| {
-        s.wallets.transfer::<Eth>(from, to, &network_id, amount)
+        s.wallets.approve(owner, spender, &asset, amount, expires_at, ic_cdk::api::time())
     })?;
     Ok(())
 }
 
-pub async fn withdraw_erc20(from: Principal, to: H160, network_id: u32, token: H160, amount: U256) -> Result<(), HarmonizeError> {
-    mutate_state(|s| {
-        s.wallets.debit::<Erc20>(from, &(network_id, token), amount)
+pub fn allowance(owner: Account, spender: Account, asset: AssetId) -> Allowance {
+    read_state(|s| {
+        s.wallets.allowance(owner, spender, &asset, ic_cdk::api::time())
+    })
+}
+
+pub fn transfer_from(spender: Account, from: Account, to: Account, asset: AssetId, amount: U256) -> Result<(), HarmonizeError> {
+    pause::check(PausableOperation::Transfers, asset.chain_id())?;
+    compliance::check_transfer(&spender, &from)?;
+    compliance::check_transfer(&from, &to)?;
+    mutate_state(|s| -> Result<(), HarmonizeError> {
+        let (treasury, fee) = (s.treasury_account(), s.fees.quote(Operation::Transfer, &asset, amount));
+        s.wallets.transfer_from(spender, from, to, &asset, amount, Some((treasury, fee)), ic_cdk::api::time())?;
+        s.record_transaction(
+            history::transfer_entries(from, to, asset, amount)
+                .into_iter()
+                .chain(fees::fee_entries(from, treasury, Operation::Transfer, asset, fee)),
+        );
+        Ok(())
+    })
+}
+
+/// Withdraws ERC-20 tokens to an address on the network and returns the hash of the transaction.
+pub async fn withdraw_erc20(from: Account, to: H160, network_id: u32, token: H160, amount: U256) -> Result<String, HarmonizeError> {
+    let asset = Erc20::asset_id(&(network_id, token));
+    // A token mapped to a canonical asset shares its liquidity with the canonical balances
+    let canonical = mutate_state(|s| s.canonical_assets.reserve_token_liquidity(network_id, token, amount))?;
+    let result = withdraw_with(from, to, asset, asset, amount, safe::transfer_erc20(network_id, token, from, to, amount, None, None)).await;
+    if let (Some(id), Err(_)) = (canonical, &result) {
+        mutate_state(|s| s.canonical_assets.restore_liquidity(id, network_id, amount));
+    }
+    result
+}
+
+/// Withdraws ether to an address on the network and returns the hash of the transaction.
+pub async fn withdraw_eth(from: Account, to: H160, network_id: u32, amount: U256) -> Result<String, HarmonizeError> {
+    let asset = Eth::asset_id(&network_id);
+    withdraw_with(from, to, asset, asset, amount, safe::transfer_eth(network_id, from, to, amount, None, None)).await
+}
+
+/// Holds `amount` of `asset` and the withdrawal fee while `transfer` is in flight, then
+/// captures them once the transaction is mined or releases them if it was not
+/// broadcast or got reverted.
+///
+/// A transaction that was broadcast without a receipt keeps the hold until it is
+/// settled, see [`crate::settlement`].
+///
+/// The withdrawal limits apply to `outflow`, the asset that leaves the canister's
+/// address, which differs from `asset` for canonical assets.
+///
+/// `transfer` is only polled once the hold is in place.
+pub async fn withdraw_with(
+    from: Account,
+    to: H160,
+    asset: AssetId,
+    outflow: AssetId,
+    amount: U256,
+    transfer: impl Future<Output = Result<TransactionReceipt, TransactionError>>,
+) -> Result<String, HarmonizeError> {
+    pause::check(PausableOperation::Withdrawals, outflow.chain_id())?;
+    compliance::check_withdrawal(&from, to)?;
+    let now = ic_cdk::api::time();
+    let hold = mutate_state(|s| -> Result<_, HarmonizeError> {
+        let fee = s.fees.quote(Operation::Withdrawal, &asset, amount);
+        let total = amount.checked_add(fee).ok_or(WalletError::from(BalanceError::ArithmeticOverflow))?;
+        let limited = s.rate_limits.reserve(from.owner, outflow, amount, now)?;
+        if let Err(e) = s.wallets.hold_asset(from, &asset, total) {
+            if let Some(limited) = &limited {
+                s.rate_limits.release(limited);
+            }
+            return Err(e.into());
+        }
+        s.record_transaction([(from, TransactionKind::WithdrawalReservation { asset, amount: total.into() })]);
+        Ok(WithdrawalHold { from, to: to.into(), asset, outflow, amount: amount.into(), fee: fee.into(), limited })
     })?;
-    let caller = ic_cdk::caller();
-    let result = safe::transfer_erc20(network_id, token, caller, to, amount, None, None).await;
-    if let Err(e) = result {
-        mutate_state(|s| {
-            s.wallets.credit::<Erc20>(from, &(network_id, token), amount)
-        })?;
-        return Err(e.into());
+    match transfer.await {
+        Ok(receipt) => {
+            if let Err(e) = mutate_state(|s| complete_withdrawal(s, &hold, receipt.transactionHash.clone())) {
+                println!("Error completing withdrawal {}: {:?}", receipt.transactionHash, e);
+            }
+            Ok(receipt.transactionHash)
+        }
+        // The transaction may still be mined, so the hold is kept until it is settled
+        Err(TransactionError::Unconfirmed { tx_hash, reason }) => {
+            println!("Withdrawal {} was sent but not confirmed: {}", tx_hash, reason);
+            mutate_state(|s| s.settlements.hold_withdrawal(&tx_hash, hold));
+            Ok(tx_hash)
+        }
+        Err(e) => {
+            mutate_state(|s| refund_withdrawal(s, &hold))?;
+            Err(e.into())
+        }
     }
+}
+
+/// Captures the held amount and fee of a withdrawal whose transaction was mined.
+pub fn complete_withdrawal(s: &mut State, hold: &WithdrawalHold, tx_hash: String) -> Result<(), WalletError> {
+    let (asset, amount, fee) = (hold.asset, hold.amount.0, hold.fee.0);
+    let total = amount.checked_add(fee).ok_or(BalanceError::ArithmeticOverflow)?;
+    s.wallets.capture_asset(hold.from, &asset, total)?;
+    let treasury = s.treasury_account();
+    s.wallets.credit_asset(treasury, &asset, fee)?;
+    let withdrawal = TransactionKind::Withdrawal { asset, amount: hold.amount, to: hold.to, tx_hash };
+    s.record_transaction(
+        std::iter::once((hold.from, withdrawal)).chain(fees::fee_entries(hold.from, treasury, Operation::Withdrawal, asset, fee)),
+    );
     Ok(())
 }
 
-pub async fn withdraw_eth(from: Principal, to: H160, network_id: u32, amount: U256) -> Result<(), HarmonizeError> {
-    mutate_state(|s| {
-        s.wallets.debit::<Eth>(from, &network_id, amount)
-    })?;
-    let caller = ic_cdk::caller();
-    let result = safe::transfer_eth(network_id, caller, to, amount, None, None).await;
-    if let Err(e) = result {
-        mutate_state(|s| {
-            s.wallets.credit::<Eth>(from, &network_id, amount)
-        })?;
-        return Err(e.into());
+/// Gives back the held amount and fee of a withdrawal that did not happen, and its withdrawal limit usage.
+pub fn refund_withdrawal(s: &mut State, hold: &WithdrawalHold) -> Result<(), WalletError> {
+    if let Some(limited) = &hold.limited {
+        s.rate_limits.release(limited);
     }
+    let total = hold.amount.0.checked_add(hold.fee.0).ok_or(BalanceError::ArithmeticOverflow)?;
+    s.wallets.release_asset(hold.from, &hold.asset, total)?;
+    s.record_transaction([(hold.from, TransactionKind::WithdrawalRefund { asset: hold.asset, amount: total.into() })]);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_between_assets_is_isolated() {
+        let mut wallets = Wallets::<u32>::new();
+        let token = H160::repeat_byte(1);
+        wallets.credit::<Eth>(1, &31337, U256::from(100)).unwrap();
+        wallets.credit::<Erc20>(1, &(31337, token), U256::from(50)).unwrap();
+
+        wallets.transfer::<Erc20>(1, 2, &(31337, token), U256::from(20)).unwrap();
+        assert!(wallets.transfer::<Eth>(2, 1, &31337, U256::from(1)).is_err());
+
+        assert_eq!(wallets.get_balance_or_default::<Eth>(1, &31337), U256::from(100));
+        assert_eq!(wallets.get_balance_or_default::<Erc20>(1, &(31337, token)), U256::from(30));
+        assert_eq!(
+            wallets.get_asset_balance(2, &AssetId::Erc20 { chain_id: 31337, token: token.into() }),
+            Some(&U256::from(20))
+        );
+    }
+
+    #[test]
+    fn test_transfer_from_uses_up_allowance() {
+        let mut wallets = Wallets::<u32>::new();
+        let asset = AssetId::Native { chain_id: 31337 };
+        wallets.credit_asset(1, &asset, U256::from(100)).unwrap();
+        wallets.approve(1, 2, &asset, U256::from(30), Some(10), 0).unwrap();
+
+        wallets.transfer_from(2, 1, 3, &asset, U256::from(20), None, 5).unwrap();
+        assert_eq!(wallets.allowance(1, 2, &asset, 5).amount.0, U256::from(10));
+        assert!(matches!(
+            wallets.transfer_from(2, 1, 3, &asset, U256::from(20), None, 5),
+            Err(WalletError::InsufficientAllowance)
+        ));
+        assert!(matches!(
+            wallets.transfer_from(2, 1, 3, &asset, U256::from(10), None, 10),
+            Err(WalletError::AllowanceExpired)
+        ));
+        assert_eq!(wallets.get_asset_balance(3, &asset), Some(&U256::from(20)));
+    }
+
+    #[test]
+    fn test_default_subaccount_is_the_principal_account() {
+        let owner = Principal::anonymous();
+        let mut wallets = Wallets::<Account>::new();
+        wallets.credit::<Eth>(Account::new(owner, Some(DEFAULT_SUBACCOUNT)), &31337, U256::from(10)).unwrap();
+        wallets.credit::<Eth>(Account::new(owner, Some([1; 32])), &31337, U256::from(5)).unwrap();
+
+        assert_eq!(wallets.get_balance_or_default::<Eth>(owner.into(), &31337), U256::from(10));
+        assert_eq!(wallets.get_balance_or_default::<Eth>(Account::new(owner, Some([1; 32])), &31337), U256::from(5));
+    }
+
+    #[test]
+    fn test_batch_transfer_is_all_or_nothing() {
+        let mut wallets = Wallets::<u32>::new();
+        let eth = AssetId::Native { chain_id: 31337 };
+        let coin = AssetId::Erc20 { chain_id: 31337, token: H160::repeat_byte(1).into() };
+        wallets.credit_asset(1, &eth, U256::from(100)).unwrap();
+        wallets.credit_asset(1, &coin, U256::from(10)).unwrap();
+
+        let rejected = wallets
+            .batch_transfer(&[
+                (1, 2, eth, U256::from(60)),
+                (1, 3, eth, U256::from(60)),
+                (1, 2, coin, U256::from(5)),
+            ])
+            .unwrap_err();
+        assert_eq!(rejected.iter().map(|(index, _)| *index).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(wallets.get_asset_balance(1, &eth), Some(&U256::from(100)));
+        assert_eq!(wallets.get_asset_balance(2, &coin), None);
+
+        // Later legs may spend what earlier legs credited
+        wallets
+            .batch_transfer(&[(1, 2, eth, U256::from(60)), (2, 3, eth, U256::from(50)), (1, 3, coin, U256::from(10))])
+            .unwrap();
+        assert_eq!(wallets.get_asset_balance(1, &eth), Some(&U256::from(40)));
+        assert_eq!(wallets.get_asset_balance(2, &eth), Some(&U256::from(10)));
+        assert_eq!(wallets.get_asset_balance(3, &eth), Some(&U256::from(50)));
+        assert_eq!(wallets.get_asset_balance(3, &coin), Some(&U256::from(10)));
+    }
+
+    #[test]
+    fn test_held_balance_cannot_be_spent() {
+        let mut wallets = Wallets::<u32>::new();
+        let asset = AssetId::Native { chain_id: 31337 };
+        wallets.credit_asset(1, &asset, U256::from(100)).unwrap();
+
+        wallets.hold_asset(1, &asset, U256::from(70)).unwrap();
+        assert!(wallets.transfer_asset(1, 2, &asset, U256::from(40)).is_err());
+        assert_eq!(wallets.total_balance(&asset), U256::from(100));
+
+        wallets.capture_asset(1, &asset, U256::from(50)).unwrap();
+        wallets.release_asset(1, &asset, U256::from(20)).unwrap();
+        assert!(matches!(
+            wallets.release_asset(1, &asset, U256::from(1)),
+            Err(WalletError::BalanceError(BalanceError::InsufficientHeld))
+        ));
+        assert_eq!(wallets.get_asset_balance(1, &asset), Some(&U256::from(50)));
+        assert_eq!(wallets.get_asset_held(1, &asset), None);
+    }
+}
@@ -25,6 +25,21 @@ contract Endpoint {
         uint256 amount
     );
 
+    event DepositEthToSubaccount(
+        address indexed sender,
+        bytes32 indexed recipient,
+        bytes32 subaccount,
+        uint256 amount
+    );
+
+    event DepositErc20ToSubaccount(
+        address indexed sender,
+        bytes32 indexed recipient,
+        address indexed token,
+        bytes32 subaccount,
+        uint256 amount
+    );
+
     address public harmonize;
 
     constructor(address _harmonize) {
@@ -33,13 +48,32 @@ contract Endpoint {
     }
 
     function depositEth(bytes32 recipient) external payable {
+        _forwardEth();
+        emit DepositEth(msg.sender, recipient, msg.value);
+    }
+
+    function depositEthToSubaccount(bytes32 recipient, bytes32 subaccount) external payable {
+        _forwardEth();
+        emit DepositEthToSubaccount(msg.sender, recipient, subaccount, msg.value);
+    }
+
+    function depositErc20(bytes32 recipient, address token, uint256 amount) external {
+        uint256 amountReceived = _forwardErc20(token, amount);
+        emit DepositErc20(msg.sender, recipient, token, amountReceived);
+    }
+
+    function depositErc20ToSubaccount(bytes32 recipient, bytes32 subaccount, address token, uint256 amount) external {
+        uint256 amountReceived = _forwardErc20(token, amount);
+        emit DepositErc20ToSubaccount(msg.sender, recipient, token, subaccount, amountReceived);
+    }
+
+    function _forwardEth() internal {
         require(msg.value > 0, DepositAmountZero());
         (bool status,) = harmonize.call{value: msg.value}("");
         require(status, DepositFailed());
-        emit DepositEth(msg.sender, recipient, msg.value);
     }
 
-    function depositErc20(bytes32 recipient, address token, uint256 amount) external {
+    function _forwardErc20(address token, uint256 amount) internal returns (uint256) {
         require(token != address(0), EndpointZeroAddress());
         require(amount > 0, DepositAmountZero());
 
@@ -52,7 +86,6 @@ contract Endpoint {
 
         uint256 amountReceived = newBalance - currentBalance;
         require(amountReceived > 0, ReceivedAmountZero());
-
-        emit DepositErc20(msg.sender, recipient, token, amountReceived);
+        return amountReceived;
     }
 }
@@ -25,28 +25,53 @@ import type {
 
 export interface EndpointInterface extends Interface {
   getFunction(
-    nameOrSignature: "depositErc20" | "depositEth" | "harmonize"
+    nameOrSignature:
+      | "depositErc20"
+      | "depositErc20ToSubaccount"
+      | "depositEth"
+      | "depositEthToSubaccount"
+      | "harmonize"
   ): FunctionFragment;
 
   getEvent(
-    nameOrSignatureOrTopic: "DepositErc20" | "DepositEth"
+    nameOrSignatureOrTopic:
+      | "DepositErc20"
+      | "DepositErc20ToSubaccount"
+      | "DepositEth"
+      | "DepositEthToSubaccount"
   ): EventFragment;
 
   encodeFunctionData(
     functionFragment: "depositErc20",
     values: [BytesLike, AddressLike, BigNumberish]
   ): string;
+  encodeFunctionData(
+    functionFragment: "depositErc20ToSubaccount",
+    values: [BytesLike, BytesLike, AddressLike, BigNumberish]
+  ): string;
   encodeFunctionData(
     functionFragment: "depositEth",
     values: [BytesLike]
   ): string;
+  encodeFunctionData(
+    functionFragment: "depositEthToSubaccount",
+    values: [BytesLike, BytesLike]
+  ): string;
   encodeFunctionData(functionFragment: "harmonize", values?: undefined): string;
 
   decodeFunctionResult(
     functionFragment: "depositErc20",
     data: BytesLike
   ): Result;
+  decodeFunctionResult(
+    functionFragment: "depositErc20ToSubaccount",
+    data: BytesLike
+  ): Result;
   decodeFunctionResult(functionFragment: "depositEth", data: BytesLike): Result;
+  decodeFunctionResult(
+    functionFragment: "depositEthToSubaccount",
+    data: BytesLike
+  ): Result;
   decodeFunctionResult(functionFragment: "harmonize", data: BytesLike): Result;
 }
 
@@ -75,6 +100,34 @@ export namespace DepositErc20Event {
   export type LogDescription = TypedLogDescription<Event>;
 }
 
+export namespace DepositErc20ToSubaccountEvent {
+  export type InputTuple = [
+    sender: AddressLike,
+    recipient: BytesLike,
+    token: AddressLike,
+    subaccount: BytesLike,
+    amount: BigNumberish
+  ];
+  export type OutputTuple = [
+    sender: string,
+    recipient: string,
+    token: string,
+    subaccount: string,
+    amount: bigint
+  ];
+  export interface OutputObject {
+    sender: string;
+    recipient: string;
+    token: string;
+    subaccount: string;
+    amount: bigint;
+  }
+  export type Event = TypedContractEvent<InputTuple, OutputTuple, OutputObject>;
+  export type Filter = TypedDeferredTopicFilter<Event>;
+  export type Log = TypedEventLog<Event>;
+  export type LogDescription = TypedLogDescription<Event>;
+}
+
 export namespace DepositEthEvent {
   export type InputTuple = [
     sender: AddressLike,
@@ -93,6 +146,31 @@ export namespace DepositEthEvent {
   export type LogDescription = TypedLogDescription<Event>;
 }
 
+export namespace DepositEthToSubaccountEvent {
+  export type InputTuple = [
+    sender: AddressLike,
+    recipient: BytesLike,
+    subaccount: BytesLike,
+    amount: BigNumberish
+  ];
+  export type OutputTuple = [
+    sender: string,
+    recipient: string,
+    subaccount: string,
+    amount: bigint
+  ];
+  export interface OutputObject {
+    sender: string;
+    recipient: string;
+    subaccount: string;
+    amount: bigint;
+  }
+  export type Event = TypedContractEvent<InputTuple, OutputTuple, OutputObject>;
+  export type Filter = TypedDeferredTopicFilter<Event>;
+  export type Log = TypedEventLog<Event>;
+  export type LogDescription = TypedLogDescription<Event>;
+}
+
 export interface Endpoint extends BaseContract {
   connect(runner?: ContractRunner | null): Endpoint;
   waitForDeployment(): Promise<this>;
@@ -142,8 +220,25 @@ export interface Endpoint extends BaseContract {
     "nonpayable"
   >;
 
+  depositErc20ToSubaccount: TypedContractMethod<
+    [
+      recipient: BytesLike,
+      subaccount: BytesLike,
+      token: AddressLike,
+      amount: BigNumberish
+    ],
+    [void],
+    "nonpayable"
+  >;
+
   depositEth: TypedContractMethod<[recipient: BytesLike], [void], "payable">;
 
+  depositEthToSubaccount: TypedContractMethod<
+    [recipient: BytesLike, subaccount: BytesLike],
+    [void],
+    "payable"
+  >;
+
   harmonize: TypedContractMethod<[], [string], "view">;
 
   getFunction<T extends ContractMethod = ContractMethod>(
@@ -157,9 +252,28 @@ export interface Endpoint extends BaseContract {
     [void],
     "nonpayable"
   >;
+  getFunction(
+    nameOrSignature: "depositErc20ToSubaccount"
+  ): TypedContractMethod<
+    [
+      recipient: BytesLike,
+      subaccount: BytesLike,
+      token: AddressLike,
+      amount: BigNumberish
+    ],
+    [void],
+    "nonpayable"
+  >;
   getFunction(
     nameOrSignature: "depositEth"
   ): TypedContractMethod<[recipient: BytesLike], [void], "payable">;
+  getFunction(
+    nameOrSignature: "depositEthToSubaccount"
+  ): TypedContractMethod<
+    [recipient: BytesLike, subaccount: BytesLike],
+    [void],
+    "payable"
+  >;
   getFunction(
     nameOrSignature: "harmonize"
   ): TypedContractMethod<[], [string], "view">;
@@ -171,6 +285,13 @@ export interface Endpoint extends BaseContract {
     DepositErc20Event.OutputTuple,
     DepositErc20Event.OutputObject
   >;
+  getEvent(
+    key: "DepositErc20ToSubaccount"
+  ): TypedContractEvent<
+    DepositErc20ToSubaccountEvent.InputTuple,
+    DepositErc20ToSubaccountEvent.OutputTuple,
+    DepositErc20ToSubaccountEvent.OutputObject
+  >;
   getEvent(
     key: "DepositEth"
   ): TypedContractEvent<
@@ -178,6 +299,13 @@ export interface Endpoint extends BaseContract {
     DepositEthEvent.OutputTuple,
     DepositEthEvent.OutputObject
   >;
+  getEvent(
+    key: "DepositEthToSubaccount"
+  ): TypedContractEvent<
+    DepositEthToSubaccountEvent.InputTuple,
+    DepositEthToSubaccountEvent.OutputTuple,
+    DepositEthToSubaccountEvent.OutputObject
+  >;
 
   filters: {
     "DepositErc20(address,bytes32,address,uint256)": TypedContractEvent<
@@ -191,6 +319,17 @@ export interface Endpoint extends BaseContract {
       DepositErc20Event.OutputObject
     >;
 
+    "DepositErc20ToSubaccount(address,bytes32,address,bytes32,uint256)": TypedContractEvent<
+      DepositErc20ToSubaccountEvent.InputTuple,
+      DepositErc20ToSubaccountEvent.OutputTuple,
+      DepositErc20ToSubaccountEvent.OutputObject
+    >;
+    DepositErc20ToSubaccount: TypedContractEvent<
+      DepositErc20ToSubaccountEvent.InputTuple,
+      DepositErc20ToSubaccountEvent.OutputTuple,
+      DepositErc20ToSubaccountEvent.OutputObject
+    >;
+
     "DepositEth(address,bytes32,uint256)": TypedContractEvent<
       DepositEthEvent.InputTuple,
       DepositEthEvent.OutputTuple,
@@ -201,5 +340,16 @@ export interface Endpoint extends BaseContract {
       DepositEthEvent.OutputTuple,
       DepositEthEvent.OutputObject
     >;
+
+    "DepositEthToSubaccount(address,bytes32,bytes32,uint256)": TypedContractEvent<
+      DepositEthToSubaccountEvent.InputTuple,
+      DepositEthToSubaccountEvent.OutputTuple,
+      DepositEthToSubaccountEvent.OutputObject
+    >;
+    DepositEthToSubaccount: TypedContractEvent<
+      DepositEthToSubaccountEvent.InputTuple,
+      DepositEthToSubaccountEvent.OutputTuple,
+      DepositEthToSubaccountEvent.OutputObject
+    >;
   };
 }
@@ -139,6 +139,8 @@ def wait_for_next_update(chain_id=31337):
         print("Current block number:", current_block_number)
         if current_block_number != block_number:
             break
+        # Deposits are only credited once the next block confirms them
+        mine_block(chain_id)
         sleep(1)
 
 def mine_block(chain_id=None):
@@ -1,10 +1,10 @@
 import os
 import json
-from time import sleep
+from time import sleep, time_ns
 from uuid import uuid4
 from web3 import Web3
 from eth_account.messages import encode_defunct
-from config import w3, connect, get_default_identities, get_endpoint_abi, get_wallet_id, assert_ok, assert_err, assert_fail, get_default_principals, get_ganache_dev_accounts, unwrap_ok, unwrap_value, get_coin_address, get_coin_abi, wait_for_next_update, get_w3
+from config import w3, connect, get_default_identities, get_endpoint_abi, get_wallet_id, assert_ok, assert_err, assert_fail, get_default_principals, get_ganache_dev_accounts, unwrap_ok, unwrap_err, unwrap_value, get_coin_address, get_coin_abi, wait_for_next_update, get_w3
 from ic import Principal
 
 # The tests in this suite are designed to be run in order, as they depend on the canister state of the previous tests.
@@ -74,6 +74,21 @@ def test_deposit_er20():
     # Sleep for a bit to allow the transaction to be processed
     wait_for_next_update(chain_id)
 
+    # The coin is not listed yet, so the deposit is held
+    user_a_coin_balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert user_a_coin_balance == "0", "User A's balance should still be 0"
+    held_deposits = unwrap_value(harmonize.get_held_deposits(chain_id))
+    assert len(held_deposits) == 1, "The deposit should be held"
+    assert held_deposits[0]['amount'] == "100", "The held deposit should be 100 coins"
+
+    # Only the owner can list the coin
+    harmonize_owner = connect(index=0)
+    metadata = unwrap_ok(harmonize_owner.add_token(chain_id, coin_address))
+    assert metadata == {'name': 'Coin', 'symbol': 'COIN', 'decimals': 18}, "The coin metadata should be fetched from the chain"
+    tokens = unwrap_value(harmonize.get_tokens(chain_id))
+    assert [token['token'].lower() for token in tokens] == [coin_address.lower()], "The coin should be listed"
+    assert unwrap_value(harmonize.get_held_deposits(chain_id)) == [], "The held deposit should be released"
+
     # Check that the balance has been updated as expected
     user_a_coin_balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
     assert user_a_coin_balance == "100", "User A's balance should be 100"
@@ -101,7 +116,7 @@ def test_transfer_erc20():
     assert user_b_coin_balance == "0", "User B's balance should be 0"
 
     # Transfer 50 coins from user A to user B
-    response = harmonize.transfer_erc20(str(user_a), str(user_b), chain_id, coin_address, "50")
+    response = harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "50")
     assert_ok(response)
 
     # Check that the balance has been updated as expected
@@ -110,6 +125,14 @@ def test_transfer_erc20():
     user_b_coin_balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address))
     assert user_b_coin_balance == "50", "User B's balance should be 50"
 
+    # Transfers always debit the caller, so user B cannot spend user A's coins
+    harmonize = connect(index=2)
+    response = harmonize.transfer_erc20(str(user_a), chain_id, coin_address, "60")
+    assert_err(response)
+
+    user_a_coin_balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert user_a_coin_balance == "50", "User A's balance should still be 50"
+
 def test_deposit_eth():
     (owner, user_a, user_b) = get_default_principals()
     (account_a, account_b) = get_ganache_dev_accounts()
@@ -166,7 +189,7 @@ def test_transfer_eth():
 
     # Transfer 50 coins from user A to user B
     one_half_eth = w3.to_wei(0.5, 'ether')
-    response = harmonize.transfer_eth(str(user_a), str(user_b), chain_id, str(one_half_eth))
+    response = harmonize.transfer_eth(str(user_b), chain_id, str(one_half_eth))
     assert_ok(response)
 
     # Check that the balance has been updated as expected
@@ -235,4 +258,493 @@ def test_withdraw_eth():
     assert_ok(response)
 
     # Give ganache some time to process the transaction
-    sleep(2)
\ No newline at end of file
+    sleep(2)
+
+
+def test_transfer_from_erc20():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    asset = {'Erc20': {'chain_id': chain_id, 'token': coin_address}}
+
+    account_a = {'owner': user_a, 'subaccount': []}
+    account_b = {'owner': user_b, 'subaccount': []}
+
+    harmonize_a = connect(index=1)
+    harmonize_b = connect(index=2)
+
+    # User B cannot spend user A's coins without an allowance
+    response = harmonize_b.transfer_from(account_a, account_b, asset, "10", [])
+    assert_err(response)
+
+    # User A allows user B to spend 20 coins
+    response = harmonize_a.approve(account_b, asset, "20", [], [])
+    assert_ok(response)
+    allowance = unwrap_value(harmonize_a.allowance(account_a, account_b, asset))
+    assert allowance['amount'] == "20", "The allowance should be 20"
+
+    # User B spends 15 coins of the allowance
+    response = harmonize_b.transfer_from(account_a, account_b, asset, "15", [])
+    assert_ok(response)
+    allowance = unwrap_value(harmonize_a.allowance(account_a, account_b, asset))
+    assert allowance['amount'] == "5", "The allowance should be 5"
+
+    # The remaining allowance does not cover another 10 coins
+    response = harmonize_b.transfer_from(account_a, account_b, asset, "10", [])
+    assert_err(response)
+
+    user_a_coin_balance = unwrap_value(harmonize_a.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert user_a_coin_balance == "35", "User A's balance should be 35"
+    user_b_coin_balance = unwrap_value(harmonize_a.get_erc20_balance(str(user_b), chain_id, coin_address))
+    assert user_b_coin_balance == "15", "User B's balance should be 15"
+
+def test_icrc1_transfer():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    ledger = {'Erc20': {'chain_id': chain_id, 'token': coin_address}}
+
+    harmonize = connect(index=1)
+
+    balance = unwrap_value(harmonize.icrc1_balance_of(ledger, {'owner': user_a, 'subaccount': []}))
+    assert balance == 35, "User A's balance should be 35"
+
+    response = harmonize.icrc1_transfer(ledger, {
+        'from_subaccount': [],
+        'to': {'owner': user_b, 'subaccount': []},
+        'fee': [],
+        'memo': [],
+        'created_at_time': [],
+        'amount': 5,
+    })
+    assert_ok(response)
+
+    balance = unwrap_value(harmonize.icrc1_balance_of(ledger, {'owner': user_b, 'subaccount': []}))
+    assert balance == 20, "User B's balance should be 20"
+    total_supply = unwrap_value(harmonize.icrc1_total_supply(ledger))
+    assert total_supply == 50, "The total supply should be 50"
+
+def test_get_transactions():
+    (owner, user_a, user_b) = get_default_principals()
+
+    harmonize = connect(index=1)
+
+    # User A deposited coins and ether, then sent some of them to user B
+    page = unwrap_value(harmonize.get_transactions(str(user_a), 0, 100))
+    kinds = [list(tx['kind'].keys())[0] for tx in page['transactions']]
+    assert page['total'] == len(kinds), "All transactions should fit on one page"
+    assert kinds.count('Deposit') == 2, "User A should have two deposits"
+    assert 'TransferOut' in kinds, "User A should have sent a transfer"
+
+    # Pages are bounded by the limit
+    page = unwrap_value(harmonize.get_transactions(str(user_a), 1, 1))
+    assert len(page['transactions']) == 1, "The page should hold a single transaction"
+
+    page = unwrap_value(harmonize.get_transactions(str(user_b), 0, 100))
+    kinds = [list(tx['kind'].keys())[0] for tx in page['transactions']]
+    assert 'TransferIn' in kinds, "User B should have received a transfer"
+    assert 'Withdrawal' in kinds, "User B should have withdrawn"
+    assert 'WithdrawalReservation' in kinds, "The withdrawal should have been held first"
+    assert 'GasReservation' in kinds, "The gas of the withdrawal should have been held first"
+
+def test_get_balances():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    coin_address = get_coin_address(chain_id)
+
+    harmonize = connect(index=1)
+
+    # User A holds ether and coins on a single network
+    balances = unwrap_value(harmonize.get_balances(str(user_a)))
+    assert len(balances) == 1, "User A should hold assets on one network"
+    assert balances[0]['chain_id'] == chain_id, f"User A's assets should be on network {chain_id}"
+    assert balances[0]['native'] == str(w3.to_wei(0.5, 'ether')), "User A should hold 0.5 ETH"
+    tokens = {token['token'].lower(): token['amount'] for token in balances[0]['erc20']}
+    assert tokens == {coin_address.lower(): "30"}, "User A should hold 30 coins"
+    assert balances[0]['erc20'][0]['decimals'] == [18], "The coin balance should carry its decimals"
+
+def test_subaccounts():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    subaccount = bytes([1] * 32)
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    coin = w3.eth.contract(address=coin_address, abi=get_coin_abi())
+
+    harmonize = connect(index=1)
+    endpoint_address = w3.to_checksum_address(unwrap_value(harmonize.get_endpoint_address(chain_id)))
+    endpoint = w3.eth.contract(address=endpoint_address, abi=get_endpoint_abi())
+
+    # Deposit 10 coins into a subaccount of user A
+    tx_hash = coin.functions.approve(endpoint_address, 10).transact({'from': account_a.address})
+    w3.eth.wait_for_transaction_receipt(tx_hash)
+    tx_hash = endpoint.functions.depositErc20ToSubaccount(principal_to_bytes32(user_a), subaccount, coin_address, 10).transact({'from': account_a.address})
+    w3.eth.wait_for_transaction_receipt(tx_hash)
+    wait_for_next_update(chain_id)
+
+    # The subaccount is credited, the default account is untouched
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address, [subaccount]))
+    assert balance == "10", "User A's subaccount should hold 10 coins"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address, []))
+    assert balance == "30", "User A's default account should still hold 30 coins"
+
+    # Move 4 coins from the subaccount to the default account of user B
+    response = harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "4", [subaccount], [])
+    assert_ok(response)
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address, [subaccount]))
+    assert balance == "6", "User A's subaccount should hold 6 coins"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address, []))
+    assert balance == "24", "User B's default account should hold 24 coins"
+
+def test_batch_transfer():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    subaccount = bytes([1] * 32)
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    coin = {'Erc20': {'chain_id': chain_id, 'token': coin_address}}
+    eth = {'Native': {'chain_id': chain_id}}
+    account_b = {'owner': user_b, 'subaccount': []}
+
+    harmonize = connect(index=1)
+
+    # User A only holds 0.5 ETH, so the second leg is rejected and nothing moves
+    response = harmonize.batch_transfer([
+        {'from_subaccount': [], 'to': account_b, 'asset': coin, 'amount': "5"},
+        {'from_subaccount': [], 'to': account_b, 'asset': eth, 'amount': str(w3.to_wei(1, 'ether'))},
+    ])
+    error = unwrap_err(response)
+    assert [leg['index'] for leg in error['BatchTransferError']['rejected']] == [1], "Only the second leg should be rejected"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address))
+    assert balance == "24", "User B's balance should still be 24"
+
+    # A valid batch moves every leg
+    response = harmonize.batch_transfer([
+        {'from_subaccount': [], 'to': account_b, 'asset': coin, 'amount': "5"},
+        {'from_subaccount': [subaccount], 'to': account_b, 'asset': coin, 'amount': "2"},
+        {'from_subaccount': [], 'to': account_b, 'asset': eth, 'amount': str(w3.to_wei(0.1, 'ether'))},
+    ])
+    assert_ok(response)
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address))
+    assert balance == "31", "User B's balance should be 31"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address, [subaccount]))
+    assert balance == "4", "User A's subaccount should hold 4 coins"
+    balance = unwrap_value(harmonize.get_eth_balance(str(user_a), chain_id))
+    assert balance == str(w3.to_wei(0.4, 'ether')), "User A should hold 0.4 ETH"
+
+def test_get_balance():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    eth = {'Native': {'chain_id': chain_id}}
+
+    harmonize = connect(index=2)
+
+    # Nothing stays held once the withdrawals have settled
+    balance = unwrap_value(harmonize.get_balance(str(user_b), eth, []))
+    assert balance['held'] == "0", "User B should have no held ether"
+    assert balance['available'] == unwrap_value(harmonize.get_eth_balance(str(user_b), chain_id)), "The available balance should match"
+
+def test_idempotent_transfer():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+
+    harmonize = connect(index=1)
+
+    # Retrying a transfer with the same memo and creation time returns the original transaction
+    memo = uuid4().bytes
+    created_at_time = time_ns()
+    transaction_id = unwrap_ok(harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "1", [], [], [memo], [created_at_time]))
+    retry_id = unwrap_ok(harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "1", [], [], [memo], [created_at_time]))
+    assert retry_id == transaction_id, "The retry should return the original transaction id"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address))
+    assert balance == "32", "User B should have received a single coin"
+
+    # Calls outside of the deduplication window are rejected
+    response = harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "1", [], [], [memo], [created_at_time - 2 * 24 * 60 * 60 * 10**9])
+    assert 'TooOld' in unwrap_err(response)['DedupError'], "The call should be too old"
+
+def test_canonical_asset():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    coin = w3.eth.contract(address=coin_address, abi=get_coin_abi())
+
+    # Only the owner can create canonical assets and map tokens to them
+    harmonize_owner = connect(index=0)
+    asset_id = unwrap_value(harmonize_owner.create_canonical_asset("Coin", "COIN", 18))
+    assert_ok(harmonize_owner.map_canonical_token(asset_id, chain_id, coin_address))
+    canonical = {'Canonical': {'id': asset_id}}
+
+    # The coins deposited before the mapping back the canonical asset as well
+    assets = unwrap_value(harmonize_owner.get_canonical_assets())
+    inventory = int(assets[asset_id]['chains'][0]['liquidity'])
+    assert inventory > 0, "The deposited coins should be part of the liquidity"
+
+    harmonize = connect(index=1)
+    endpoint_address = w3.to_checksum_address(unwrap_value(harmonize.get_endpoint_address(chain_id)))
+    endpoint = w3.eth.contract(address=endpoint_address, abi=get_endpoint_abi())
+
+    # New deposits of the coin are credited to the canonical asset
+    tx_hash = coin.functions.approve(endpoint_address, 10).transact({'from': account_a.address})
+    w3.eth.wait_for_transaction_receipt(tx_hash)
+    tx_hash = endpoint.functions.depositErc20(principal_to_bytes32(user_a), coin_address, 10).transact({'from': account_a.address})
+    w3.eth.wait_for_transaction_receipt(tx_hash)
+    wait_for_next_update(chain_id)
+
+    balance = unwrap_value(harmonize.get_balance(str(user_a), canonical, []))
+    assert balance['available'] == "10", "User A should hold 10 canonical coins"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert balance == "24", "User A's coin balance should be unchanged"
+
+    # Withdrawals fail cleanly on networks without the token or without enough liquidity
+    response = harmonize.withdraw_canonical(asset_id, account_a.address, 31338, "4")
+    assert 'UnsupportedChain' in unwrap_err(response)['CanonicalAssetError'], "The coin is not mapped on 31338"
+    response = harmonize.withdraw_canonical(asset_id, account_a.address, chain_id, str(inventory + 11))
+    assert 'InsufficientLiquidity' in unwrap_err(response)['CanonicalAssetError'], f"Only {inventory + 10} coins are available on 31337"
+
+    assert_ok(harmonize.withdraw_canonical(asset_id, account_a.address, chain_id, "4"))
+    balance = unwrap_value(harmonize.get_balance(str(user_a), canonical, []))
+    assert balance['available'] == "6", "User A should hold 6 canonical coins"
+    assets = unwrap_value(harmonize.get_canonical_assets())
+    assert assets[asset_id]['chains'][0]['liquidity'] == str(inventory + 6), f"{inventory + 6} coins of liquidity should be left on 31337"
+
+def test_fees():
+    (owner, user_a, user_b) = get_default_principals()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+    coin = {'Erc20': {'chain_id': chain_id, 'token': coin_address}}
+
+    # Only the owner can set fees, which are collected into the treasury
+    harmonize_owner = connect(index=0)
+    harmonize_owner.set_treasury(str(owner))
+    assert_ok(harmonize_owner.set_fee({'Transfer': None}, coin, [{'Flat': {'amount': "1"}}]))
+    response = harmonize_owner.set_fee({'Deposit': None}, coin, [{'BasisPoints': {'bps': 10001}}])
+    assert 'InvalidBasisPoints' in unwrap_err(response)['FeeError'], "The fee cannot exceed the amount"
+
+    harmonize = connect(index=1)
+    fee = unwrap_value(harmonize.quote_fee({'Transfer': None}, coin, "2"))
+    assert fee == "1", "The transfer fee should be 1 coin"
+
+    # The fee is paid on top of the amount
+    assert_ok(harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "2"))
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert balance == "21", "User A should have paid 2 coins and a fee of 1"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_b), chain_id, coin_address))
+    assert balance == "34", "User B should have received 2 coins"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(owner), chain_id, coin_address))
+    assert balance == "1", "The treasury should have collected the fee"
+
+    assert_ok(harmonize_owner.set_fee({'Transfer': None}, coin, []))
+    assert unwrap_value(harmonize.get_fee_schedule()) == [], "The fee should be removed"
+
+def test_withdrawal_limits():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    eth = {'Native': {'chain_id': chain_id}}
+    limit = str(w3.to_wei(0.1, 'ether'))
+
+    # Only the owner can set limits
+    harmonize_owner = connect(index=0)
+    assert_ok(harmonize_owner.set_withdrawal_limit(eth, {'User': None}, [{'amount': limit, 'window_nanos': 60 * 60 * 10**9}]))
+
+    harmonize = connect(index=1)
+    response = harmonize.withdraw_eth(account_a.address, chain_id, str(w3.to_wei(0.2, 'ether')))
+    assert 'UserLimitExceeded' in unwrap_err(response)['RateLimitError'], "The withdrawal should exceed the limit"
+    balance = unwrap_value(harmonize.get_balance(str(user_a), eth, []))
+    assert balance['held'] == "0", "Nothing should be held for a rejected withdrawal"
+
+    # Withdrawals within the limit go through and count against it
+    assert_ok(harmonize.withdraw_eth(account_a.address, chain_id, str(w3.to_wei(0.05, 'ether'))))
+    usage = unwrap_value(harmonize.get_withdrawal_limits(str(user_a)))
+    assert usage[0]['used'] == str(w3.to_wei(0.05, 'ether')), "The withdrawal should count against the limit"
+    response = harmonize.withdraw_eth(account_a.address, chain_id, str(w3.to_wei(0.06, 'ether')))
+    assert 'UserLimitExceeded' in unwrap_err(response)['RateLimitError'], "Only 0.05 ETH should remain in the window"
+
+    assert_ok(harmonize_owner.set_withdrawal_limit(eth, {'User': None}, []))
+
+def test_pause():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+
+    # Only the owner can pause operations, on one network or on all of them
+    harmonize_owner = connect(index=0)
+    harmonize_owner.set_paused({'Transfers': None}, [chain_id], True)
+    harmonize_owner.set_paused({'Withdrawals': None}, [], True)
+
+    harmonize = connect(index=1)
+    response = harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "1")
+    assert 'Paused' in unwrap_err(response)['PauseError'], "Transfers should be paused on the network"
+    response = harmonize.withdraw_eth(account_a.address, chain_id, "1")
+    assert 'Paused' in unwrap_err(response)['PauseError'], "Withdrawals should be paused everywhere"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert balance == "21", "Nothing should have moved while paused"
+
+    harmonize_owner.set_paused({'Transfers': None}, [chain_id], False)
+    harmonize_owner.set_paused({'Withdrawals': None}, [], False)
+    assert unwrap_value(harmonize.get_paused()) == [], "Every operation should be resumed"
+
+def test_roles():
+    (owner, user_a, user_b) = get_default_principals()
+
+    # The initial owner holds every role
+    harmonize_owner = connect(index=0)
+    roles = unwrap_value(harmonize_owner.get_roles(str(owner)))
+    assert sorted(role_name for role in roles for role_name in role) == ['Admin', 'Auditor', 'Operator', 'Pauser'], "The owner should hold every role"
+
+    # Only admins can grant roles
+    harmonize_a = connect(index=1)
+    assert_fail(lambda: harmonize_a.grant_role(str(user_a), {'Pauser': None}))
+    harmonize_owner.grant_role(str(user_b), {'Pauser': None})
+
+    # A pauser can pause, but cannot configure networks or read the role holders
+    harmonize_b = connect(index=2)
+    harmonize_b.set_paused({'Transfers': None}, [], True)
+    harmonize_b.set_paused({'Transfers': None}, [], False)
+    assert_fail(lambda: harmonize_b.get_role_holders())
+    assert_fail(lambda: harmonize_a.set_paused({'Transfers': None}, [], True))
+
+    assert_ok(harmonize_owner.revoke_role(str(user_b), {'Pauser': None}))
+    assert unwrap_value(harmonize_owner.get_roles(str(user_b))) == [], "User B should no longer hold any role"
+    response = harmonize_owner.revoke_role(str(owner), {'Admin': None})
+    assert 'LastAdmin' in unwrap_err(response)['RoleError'], "The last admin cannot be revoked"
+
+def test_network_config_timelock():
+    harmonize_owner = connect(index=0)
+    delay_seconds = 10
+    harmonize_owner.set_network_config_delay(delay_seconds * 10**9)
+
+    change = {
+        'rpc_services': [],
+        'rpc_service': [],
+        'last_scraped_block_number': [],
+        'block_tag': [{'Latest': None}],
+        'get_logs_address': [],
+        'nonce': [],
+    }
+
+    # Changes can no longer be applied directly
+    assert_fail(lambda: harmonize_owner.set_network_config(31338, change))
+
+    # A proposal has to wait for the delay, and can be cancelled meanwhile
+    proposal_id = unwrap_ok(harmonize_owner.propose_network_config(31338, change))
+    proposals = unwrap_value(harmonize_owner.get_network_proposals())
+    assert [proposal['id'] for proposal in proposals] == [proposal_id], "The proposal should be pending"
+    response = harmonize_owner.execute_network_config(proposal_id)
+    assert 'NotReady' in unwrap_err(response)['TimelockError'], "The proposal should not be executable yet"
+    assert_ok(harmonize_owner.cancel_network_config(proposal_id))
+    assert unwrap_value(harmonize_owner.get_network_proposals()) == [], "The proposal should be cancelled"
+
+    # Lowering the delay has to wait for the current delay as well
+    harmonize_owner.set_network_config_delay(0)
+    assert_fail(lambda: harmonize_owner.set_network_config(31338, change))
+    sleep(delay_seconds)
+
+def test_compliance():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    coin_address = w3.to_checksum_address(get_coin_address(chain_id))
+
+    # Only admins can freeze principals and block destinations
+    harmonize = connect(index=1)
+    assert_fail(lambda: harmonize.freeze_principal(str(user_b)))
+    harmonize_owner = connect(index=0)
+    harmonize_owner.freeze_principal(str(user_b))
+    harmonize_owner.block_address(account_b.address)
+    frozen = unwrap_value(harmonize_owner.get_frozen_principals())
+    assert [str(principal) for principal in frozen] == [str(user_b)], "User B should be frozen"
+
+    response = harmonize.transfer_erc20(str(user_b), chain_id, coin_address, "1")
+    assert 'AccountFrozen' in unwrap_err(response)['ComplianceError'], "Transfers to a frozen principal should be rejected"
+    response = harmonize.withdraw_eth(account_b.address, chain_id, "1")
+    assert 'DestinationBlocked' in unwrap_err(response)['ComplianceError'], "Withdrawals to a blocked address should be rejected"
+    balance = unwrap_value(harmonize.get_erc20_balance(str(user_a), chain_id, coin_address))
+    assert balance == "21", "Nothing should have moved"
+
+    harmonize_owner.unfreeze_principal(str(user_b))
+    harmonize_owner.unblock_address(account_b.address)
+    assert unwrap_value(harmonize_owner.get_frozen_principals()) == [], "No principal should be frozen"
+    assert_fail(lambda: harmonize.get_blocked_addresses())
+    assert unwrap_value(harmonize_owner.get_blocked_addresses()) == [], "No address should be blocked"
+
+def test_audit_log():
+    (owner, user_a, user_b) = get_default_principals()
+
+    # Only admins and auditors can read the log
+    harmonize_a = connect(index=1)
+    assert_fail(lambda: harmonize_a.get_audit_log(0, 10))
+
+    harmonize_owner = connect(index=0)
+    total = unwrap_value(harmonize_owner.get_audit_log(0, 0))['total']
+    harmonize_owner.set_treasury(str(user_b))
+    harmonize_owner.set_treasury(str(owner))
+
+    page = unwrap_value(harmonize_owner.get_audit_log(total, 10))
+    assert page['total'] == total + 2, "Both calls should be logged"
+    [first, second] = page['entries']
+    assert first['method'] == 'set_treasury', "The endpoint should be logged"
+    assert str(first['caller']) == str(owner), "The caller should be logged"
+    assert str(user_b) in first['new_value'][0], "The new treasury should be logged"
+    assert second['old_value'] == first['new_value'], "The old value should be the previous new value"
+
+def test_get_pending_deposits():
+    (owner, user_a, user_b) = get_default_principals()
+    (account_a, account_b) = get_ganache_dev_accounts()
+
+    chain_id = 31337
+    subaccount = bytes([2] * 32)
+
+    harmonize = connect(index=1)
+    endpoint_address = w3.to_checksum_address(unwrap_value(harmonize.get_endpoint_address(chain_id)))
+    endpoint = w3.eth.contract(address=endpoint_address, abi=get_endpoint_abi())
+
+    # A deposit stays pending until a later block confirms it
+    tx_hash = endpoint.functions.depositEthToSubaccount(principal_to_bytes32(user_a), subaccount).transact({'from': account_a.address, 'value': 1})
+    w3.eth.wait_for_transaction_receipt(tx_hash)
+    for _ in range(30):
+        pending = unwrap_value(harmonize.get_pending_deposits(str(user_a), [subaccount]))
+        if pending:
+            break
+        sleep(1)
+    assert len(pending) == 1, "The deposit should be pending"
+    assert pending[0]['required_confirmations'] == 1, "The deposit should wait for one confirmation"
+
+    wait_for_next_update(chain_id)
+    pending = unwrap_value(harmonize.get_pending_deposits(str(user_a), [subaccount]))
+    assert pending == [], "The deposit should be credited"
+    balance = unwrap_value(harmonize.get_eth_balance(str(user_a), chain_id, [subaccount]))
+    assert balance == "1", "User A's subaccount should hold the deposit"
+
+def test_max_concurrent_scrapes():
+    # Only operators can change how many networks scrape at the same time
+    harmonize_a = connect(index=1)
+    assert_fail(lambda: harmonize_a.set_max_concurrent_scrapes(1))
+
+    harmonize_owner = connect(index=0)
+    default = unwrap_value(harmonize_owner.get_max_concurrent_scrapes())
+    harmonize_owner.set_max_concurrent_scrapes(1)
+    assert unwrap_value(harmonize_owner.get_max_concurrent_scrapes()) == 1, "The cap should be updated"
+    assert_fail(lambda: harmonize_owner.set_max_concurrent_scrapes(0))
+    harmonize_owner.set_max_concurrent_scrapes(default)
+
+def test_get_network_status():
+    harmonize = connect(index=1)
+    status = unwrap_value(harmonize.get_network_status(31337))
+    assert status['max_block_spread'] == 500, "The default maximum spread should apply"
+    assert 0 < status['block_spread'] <= status['max_block_spread'], "The spread should stay within the maximum"